@@ -0,0 +1,59 @@
+#![feature(test)]
+extern crate test;
+
+use async_trait::async_trait;
+use byte_test::connection::{Connection, ConnectionStream, Error, Order, OrderDetails};
+use byte_test::order_book::{OrderBook, Side};
+use futures::StreamExt;
+use test::Bencher;
+use tokio::runtime::Runtime;
+
+const LEVELS: usize = 1_000;
+
+// A book of 1000 levels per side, bids below 10000 and asks above, which then stays idle.
+struct FullBookConnection;
+
+#[async_trait]
+impl Connection for FullBookConnection {
+    async fn stream(&self) -> Result<ConnectionStream, Error> {
+        let orders = (0..LEVELS).flat_map(|i| {
+            let quantity = 1.0 + (i % 7) as f64;
+            [
+                Ok(Order::Bid(OrderDetails { price: 9_999.0 - i as f64, quantity, event_time: None })),
+                Ok(Order::Ask(OrderDetails { price: 10_001.0 + i as f64, quantity, event_time: None })),
+            ]
+        });
+        let orders: Vec<_> = orders.collect();
+        Ok(Box::pin(futures::stream::iter(orders).chain(futures::stream::pending())))
+    }
+}
+
+fn full_book() -> (Runtime, OrderBook) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let book = runtime.block_on(async {
+        let book = OrderBook::create(FullBookConnection).await;
+        while book.depth_columnar(LEVELS).await.2.len() < LEVELS {
+            tokio::task::yield_now().await;
+        }
+        book
+    });
+    (runtime, book)
+}
+
+#[bench]
+fn top_bid_ask(b: &mut Bencher) {
+    let (runtime, book) = full_book();
+    b.iter(|| runtime.block_on(book.top_bid_ask()));
+}
+
+#[bench]
+fn depth_columnar(b: &mut Bencher) {
+    let (runtime, book) = full_book();
+    b.iter(|| runtime.block_on(book.depth_columnar(20)));
+}
+
+#[bench]
+fn fill_curve_bids(b: &mut Bencher) {
+    let (runtime, book) = full_book();
+    b.iter(|| runtime.block_on(book.fill_curve(Side::Bid, 500.0, 50)));
+}
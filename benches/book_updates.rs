@@ -0,0 +1,144 @@
+#![feature(test)]
+extern crate test;
+
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+use async_trait::async_trait;
+use byte_test::connection::{Connection, ConnectionStream, Error, Order, OrderDetails};
+use byte_test::order_book::OrderBook;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use test::Bencher;
+
+const UPDATES: usize = 10_000;
+
+// Length of the delta streams of the throughput benchmarks.
+const DELTAS: usize = 100_000;
+
+// A connection that never sends anything, so the book only holds what the benchmark applies.
+struct IdleConnection;
+
+#[async_trait]
+impl Connection for IdleConnection {
+    async fn stream(&self) -> Result<ConnectionStream, Error> {
+        Ok(Box::pin(futures::stream::pending()))
+    }
+}
+
+// Random inserts, quantity updates and removals as (bid, price, quantity), over 1000 price levels
+// per side, where each update is a removal with probability `removals`.
+fn updates(count: usize, removals: f64) -> Vec<(bool, f64, f64)> {
+    let mut rng = ChaCha8Rng::seed_from_u64(17);
+    (0..count)
+        .map(|_| {
+            let bid = rng.gen_bool(0.5);
+            let price = rng.gen_range(1..1_000) as f64 + if bid { 0.0 } else { 1_000.0 };
+            let quantity = if rng.gen_bool(removals) { 0.0 } else { rng.gen_range(0.1..10.0) };
+            (bid, price, quantity)
+        })
+        .collect()
+}
+
+fn order((bid, price, quantity): (bool, f64, f64)) -> Order {
+    let details = OrderDetails { price, quantity, event_time: None };
+    if bid {
+        Order::Bid(details)
+    } else {
+        Order::Ask(details)
+    }
+}
+
+#[bench]
+fn apply_batch(b: &mut Bencher) {
+    let updates = updates(UPDATES, 0.3);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let book = runtime.block_on(OrderBook::create(IdleConnection));
+    b.iter(|| {
+        let orders = updates.iter().copied().map(order).collect();
+        runtime.block_on(book.apply_batch(orders)).unwrap()
+    });
+}
+
+// A level ordered and compared by price alone, as the book's previous representation kept them.
+struct Level(OrderDetails);
+
+impl PartialEq for Level {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.price == other.0.price
+    }
+}
+
+impl Eq for Level {}
+
+impl Ord for Level {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.price.total_cmp(&other.0.price)
+    }
+}
+
+impl PartialOrd for Level {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The book's previous representation, sets of levels, as a baseline for apply_batch.
+#[bench]
+fn btree_set_baseline(b: &mut Bencher) {
+    let updates = updates(UPDATES, 0.3);
+    let mut bids = BTreeSet::new();
+    let mut asks = BTreeSet::new();
+    b.iter(|| {
+        let orders: Vec<_> = updates.iter().copied().map(order).collect();
+        for order in orders {
+            let (side, details) = match order {
+                Order::Bid(details) => (&mut bids, details),
+                Order::Ask(details) => (&mut asks, details),
+            };
+            if details.quantity == 0.0 {
+                side.remove(&Level(details));
+            } else {
+                side.replace(Level(details));
+            }
+        }
+    });
+}
+
+// Applies a stream of deltas one at a time, as the book's update task does for deltas arriving
+// apart. Throughput is reported in "MB/s", which reads as millions of deltas per second.
+fn apply_deltas(b: &mut Bencher, removals: f64) {
+    let updates = updates(DELTAS, removals);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let book = runtime.block_on(OrderBook::create(IdleConnection));
+    b.bytes = DELTAS as u64;
+    b.iter(|| {
+        runtime.block_on(async {
+            for &update in &updates {
+                book.apply(order(update)).await.unwrap();
+            }
+        })
+    });
+}
+
+#[bench]
+fn deltas_insert_heavy(b: &mut Bencher) {
+    apply_deltas(b, 0.1);
+}
+
+#[bench]
+fn deltas_remove_heavy(b: &mut Bencher) {
+    apply_deltas(b, 0.7);
+}
+
+#[test]
+fn workloads_have_the_requested_share_of_removals() {
+    let removals = |share| {
+        let updates = updates(DELTAS, share);
+        updates.iter().filter(|update| update.2 == 0.0).count() as f64 / DELTAS as f64
+    };
+    assert!((removals(0.1) - 0.1).abs() < 0.01);
+    assert!((removals(0.7) - 0.7).abs() < 0.01);
+    // The same seed gives the same stream.
+    assert_eq!(updates(100, 0.3), updates(100, 0.3));
+}
@@ -0,0 +1,55 @@
+#![feature(test)]
+extern crate test;
+
+use byte_test::connection::parse_depth_update;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use test::Bencher;
+
+// Depth update messages sized like a live feed: mostly one or two levels, sometimes more.
+fn depth_updates(count: usize) -> Vec<String> {
+    let mut rng = ChaCha8Rng::seed_from_u64(23);
+    (0..count)
+        .map(|i| {
+            let levels = match rng.gen_range(0..10) {
+                0..=6 => 1,
+                7..=8 => 2,
+                _ => rng.gen_range(3..20),
+            };
+            let mut bids = Vec::new();
+            let mut asks = Vec::new();
+            for _ in 0..levels {
+                let level = format!(
+                    "[\"{:.2}\",\"{:.8}\"]",
+                    rng.gen_range(26_000.0..27_000.0),
+                    rng.gen_range(0.0..2.0)
+                );
+                if rng.gen_bool(0.5) {
+                    bids.push(level);
+                } else {
+                    asks.push(level);
+                }
+            }
+            format!(
+                "{{\"e\":\"depthUpdate\",\"E\":{},\"s\":\"BTCUSDT\",\"U\":{},\"u\":{},\"b\":[{}],\"a\":[{}]}}",
+                1_684_000_000_000u64 + i as u64,
+                i * 10,
+                i * 10 + 9,
+                bids.join(","),
+                asks.join(",")
+            )
+        })
+        .collect()
+}
+
+#[bench]
+fn convert_depth_updates(b: &mut Bencher) {
+    let updates = depth_updates(1_000);
+    b.iter(|| {
+        let mut orders = 0;
+        for update in &updates {
+            orders += parse_depth_update(update).unwrap().count();
+        }
+        orders
+    });
+}
@@ -0,0 +1,112 @@
+#![feature(test)]
+extern crate test;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use byte_test::connection::{Connection, ConnectionStream, Error, Order, OrderDetails};
+use byte_test::order_book::{OrderBook, OrderBookOptions};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use test::Bencher;
+use tokio::runtime::Runtime;
+
+// Endless stream of bursts of 1000 random updates every millisecond, with bids below 100 and asks
+// above.
+struct SyntheticConnection;
+
+#[async_trait]
+impl Connection for SyntheticConnection {
+    async fn stream(&self) -> Result<ConnectionStream, Error> {
+        let mut rng = ChaCha8Rng::seed_from_u64(23);
+        let ticks = interval_stream(Duration::from_millis(1));
+        Ok(Box::pin(ticks.flat_map(move |_| {
+            let burst: Vec<_> = (0..1_000).map(|_| Ok(random_order(&mut rng))).collect();
+            futures::stream::iter(burst)
+        })))
+    }
+}
+
+fn interval_stream(period: Duration) -> impl Stream<Item = ()> {
+    futures::stream::unfold(tokio::time::interval(period), |mut interval| async move {
+        interval.tick().await;
+        Some(((), interval))
+    })
+}
+
+fn random_order(rng: &mut ChaCha8Rng) -> Order {
+    let price = rng.gen_range(1..100) as f64;
+    let quantity = if rng.gen_bool(0.3) { 0.0 } else { rng.gen_range(0.1..10.0) };
+    if rng.gen_bool(0.5) {
+        Order::Bid(OrderDetails { price, quantity, event_time: None })
+    } else {
+        Order::Ask(OrderDetails { price: price + 100.0, quantity, event_time: None })
+    }
+}
+
+fn read_top_of_book(b: &mut Bencher, options: OrderBookOptions) {
+    let lock_free = options.lock_free_snapshot;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+    let book = runtime.block_on(OrderBook::create_with_options(SyntheticConnection, options));
+    let book = Arc::new(book);
+    b.iter(|| read(&runtime, &book, lock_free));
+}
+
+// Reads the top of book from several concurrent readers.
+fn read(runtime: &Runtime, book: &Arc<OrderBook>, lock_free: bool) {
+    runtime.block_on(async {
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let book = book.clone();
+                tokio::spawn(async move {
+                    for _ in 0..1_000 {
+                        if lock_free {
+                            test::black_box(book.snapshot_fast().top_bid_ask());
+                        } else {
+                            test::black_box(book.top_bid_ask().await);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    });
+}
+
+// Readers contending with a writer that locks per update.
+#[bench]
+fn uncoalesced_reads(b: &mut Bencher) {
+    read_top_of_book(b, OrderBookOptions::default());
+}
+
+// Readers contending with a writer that locks once per 50ms of updates.
+#[bench]
+fn coalesced_reads(b: &mut Bencher) {
+    read_top_of_book(
+        b,
+        OrderBookOptions {
+            coalesce_window: Some(Duration::from_millis(50)),
+            ..Default::default()
+        },
+    );
+}
+
+// Lock-free readers of the published copy, with a writer that copies the book per update.
+#[bench]
+fn lock_free_reads(b: &mut Bencher) {
+    read_top_of_book(
+        b,
+        OrderBookOptions {
+            lock_free_snapshot: true,
+            ..Default::default()
+        },
+    );
+}
@@ -6,8 +6,6 @@ use std::collections::BinaryHeap;
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 use test::Bencher;
-use futures::StreamExt;
-use futures::Stream;
 
 // Binary heap of string.
 #[bench]
@@ -1,72 +1,390 @@
+use std::array;
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::convert::TryFrom;
-use std::convert::TryInto;
+use std::future::Future;
+use std::io::Read;
+use std::iter::Flatten;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::vec;
 
 use async_trait::async_trait;
-use futures::{future, Stream, StreamExt, TryFutureExt, TryStreamExt};
+use flate2::read::GzDecoder;
+use futures::{ready, Stream, StreamExt};
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::tungstenite::Error as TungsteniteError;
 use tokio_tungstenite::tungstenite::Message;
+use tracing::Instrument;
 
 use super::Connection;
 use super::ConnectionStream;
 use super::Error;
+use super::ExchangeTime;
+use super::InvalidPricePolicy;
+use super::NoRateLimit;
+use super::Number;
 use super::Order;
 use super::OrderDetails;
+use super::RateLimiter;
+use super::Sequencing;
+use super::Trade;
+use super::TradeStream;
+use crate::order_book::PriceKey;
 
-/// A connection to Binance for the specified symbol
-pub struct BinanceConnection {
+/// Base delay between snapshot fetch retries, multiplied by the attempt number.
+const SNAPSHOT_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Default time allowed for a snapshot request to complete.
+const DEFAULT_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of deltas buffered while waiting for the snapshot.
+const MAX_BUFFERED_DELTAS: usize = 10_000;
+
+/// Snapshot depth limits accepted by the REST API, ascending.
+const SNAPSHOT_LIMITS: [u32; 8] = [5, 10, 20, 50, 100, 500, 1000, 5000];
+
+/// Default maximum websocket message size, as in tungstenite.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Default cap for raising the maximum websocket message size.
+const DEFAULT_MAX_MESSAGE_SIZE_CAP: usize = 256 << 20;
+
+/// Path of the spot depth snapshot REST endpoint.
+const SPOT_SNAPSHOT_PATH: &str = "/api/v3/depth";
+
+/// Base URL of the spot testnet REST API.
+const TESTNET_REST_URL: &str = "https://testnet.binance.vision";
+
+/// Base URL of the spot testnet websocket streams.
+const TESTNET_STREAM_URL: &str = "wss://stream.testnet.binance.vision";
+
+/// Regional Binance exchange, each with its own hosts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BinanceRegion {
+    /// binance.com
+    #[default]
+    Global,
+    /// binance.us
+    Us,
+}
+
+impl BinanceRegion {
+    /// Base URL of the REST API.
+    pub(super) fn rest_url(&self) -> &'static str {
+        match self {
+            BinanceRegion::Global => "https://api.binance.com",
+            BinanceRegion::Us => "https://api.binance.us",
+        }
+    }
+
+    /// Base URL of the websocket streams.
+    fn stream_url(&self) -> &'static str {
+        match self {
+            BinanceRegion::Global => "wss://stream.binance.com:9443",
+            BinanceRegion::Us => "wss://stream.binance.us:9443",
+        }
+    }
+}
+
+/// Returns true if `symbol` is in Binance's format, the same in every region: the base and quote
+/// asset names concatenated in upper case, e.g. "BTCUSDT".
+pub(super) fn is_valid_symbol(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+/// Interval at which the exchange pushes depth updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpdateSpeed {
+    /// Every 100ms.
+    Ms100,
+    /// Every second.
+    #[default]
+    Ms1000,
+}
+
+/// A connection to Binance for the specified symbol, parsing prices and quantities as `P`
+pub struct BinanceConnection<P = f64> {
     symbol: String,
+    region: BinanceRegion,
+    rest_url: Option<String>,
+    stream_url: Option<String>,
+    snapshot_path: &'static str,
+    snapshot_limit: u32,
+    update_speed: UpdateSpeed,
+    snapshot_retries: u32,
+    snapshot_timeout: Option<Duration>,
+    sequencing: Sequencing,
+    invalid_prices: InvalidPricePolicy,
+    raw_frames: Option<broadcast::Sender<String>>,
+    compressed_frames: bool,
+    message_size: Arc<MessageSizeLimit>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    client: Arc<reqwest::Client>,
+    number_type: PhantomData<fn() -> P>,
 }
 
 impl BinanceConnection {
     pub fn new(symbol: &str) -> Self {
         Self {
             symbol: symbol.to_owned(),
+            region: BinanceRegion::Global,
+            rest_url: None,
+            stream_url: None,
+            snapshot_path: SPOT_SNAPSHOT_PATH,
+            snapshot_limit: 1000,
+            update_speed: UpdateSpeed::Ms1000,
+            snapshot_retries: 0,
+            snapshot_timeout: None,
+            sequencing: Sequencing::Overlapping,
+            invalid_prices: InvalidPricePolicy::Reject,
+            raw_frames: None,
+            compressed_frames: false,
+            message_size: Arc::new(MessageSizeLimit::new(
+                DEFAULT_MAX_MESSAGE_SIZE,
+                DEFAULT_MAX_MESSAGE_SIZE_CAP,
+            )),
+            rate_limiter: Arc::new(NoRateLimit),
+            client: shared_client(),
+            number_type: PhantomData,
         }
     }
+
+    /// Creates a connection to the spot testnet for the specified symbol.
+    pub fn testnet(symbol: &str) -> Self {
+        Self::new(symbol).base_urls(TESTNET_REST_URL, TESTNET_STREAM_URL)
+    }
+}
+
+impl<P> BinanceConnection<P> {
+    /// Parse prices and quantities as `Q` rather than `f64`, e.g. a decimal type.
+    pub fn number_type<Q>(self) -> BinanceConnection<Q> {
+        BinanceConnection {
+            symbol: self.symbol,
+            region: self.region,
+            rest_url: self.rest_url,
+            stream_url: self.stream_url,
+            snapshot_path: self.snapshot_path,
+            snapshot_limit: self.snapshot_limit,
+            update_speed: self.update_speed,
+            snapshot_retries: self.snapshot_retries,
+            snapshot_timeout: self.snapshot_timeout,
+            sequencing: self.sequencing,
+            invalid_prices: self.invalid_prices,
+            raw_frames: self.raw_frames,
+            compressed_frames: self.compressed_frames,
+            message_size: self.message_size,
+            rate_limiter: self.rate_limiter,
+            client: self.client,
+            number_type: PhantomData,
+        }
+    }
+
+    /// Connect to the given regional exchange, `BinanceRegion::Global` by default.
+    pub fn region(mut self, region: BinanceRegion) -> Self {
+        self.region = region;
+        self
+    }
+
+    /// Connect to the given base URLs of the REST API and websocket streams instead of the
+    /// region's, e.g. "https://testnet.binance.vision" and "wss://stream.testnet.binance.vision".
+    pub fn base_urls(mut self, rest_url: &str, stream_url: &str) -> Self {
+        self.rest_url = Some(rest_url.trim_end_matches('/').to_owned());
+        self.stream_url = Some(stream_url.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Fetch snapshots from the given path of the REST API, e.g. "/fapi/v1/depth" for futures.
+    pub(super) fn snapshot_path(mut self, path: &'static str) -> Self {
+        self.snapshot_path = path;
+        self
+    }
+
+    /// Fetch a snapshot deep enough to track `levels` levels per side, using the smallest limit
+    /// the REST API accepts that covers it, or its maximum of 5000. The default limit is 1000.
+    pub fn tracked_depth(mut self, levels: usize) -> Self {
+        self.snapshot_limit = snapshot_limit(levels);
+        self
+    }
+
+    /// Set the interval at which depth updates are pushed, every second by default.
+    pub fn update_speed(mut self, update_speed: UpdateSpeed) -> Self {
+        self.update_speed = update_speed;
+        self
+    }
+
+    /// Retry a failed snapshot fetch up to `retries` times, keeping the websocket stream open,
+    /// before failing the connection.
+    pub fn snapshot_retries(mut self, retries: u32) -> Self {
+        self.snapshot_retries = retries;
+        self
+    }
+
+    /// Fail a snapshot request that has not completed within `timeout`, so that it can be
+    /// retried or the connection fails instead of buffering deltas indefinitely. By default the
+    /// client's timeout applies, which is 10s for the shared client.
+    pub fn snapshot_timeout(mut self, timeout: Duration) -> Self {
+        self.snapshot_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the rule for accepting the first delta after the snapshot. Binance documents
+    /// `Sequencing::Overlapping`, which is the default.
+    pub fn sequencing(mut self, sequencing: Sequencing) -> Self {
+        self.sequencing = sequencing;
+        self
+    }
+
+    /// Set the handling of levels with a non-positive price. Frames containing them are rejected
+    /// by default.
+    pub fn invalid_prices(mut self, policy: InvalidPricePolicy) -> Self {
+        self.invalid_prices = policy;
+        self
+    }
+
+    /// Broadcast each raw websocket text frame, before parsing, to `subscribe_raw` subscribers.
+    /// Up to `capacity` frames are kept for slow subscribers, which skip any older frames.
+    pub fn raw_frames(mut self, capacity: usize) -> Self {
+        self.raw_frames = Some(broadcast::channel(capacity).0);
+        self
+    }
+
+    /// Accept gzip-compressed binary websocket frames, inflating them before parsing, for feeds
+    /// that send them. Binary frames are rejected by default.
+    pub fn compressed_frames(mut self, enabled: bool) -> Self {
+        self.compressed_frames = enabled;
+        self
+    }
+
+    /// Set the maximum websocket message and frame size, 64 MiB by default. If a message exceeds
+    /// it, the connection fails and the limit is raised for the next connection to fit the
+    /// message, at least doubling, up to `cap` (256 MiB by default).
+    pub fn max_message_size(mut self, size: usize, cap: usize) -> Self {
+        self.message_size = Arc::new(MessageSizeLimit::new(size, cap));
+        self
+    }
+
+    /// Wait for `rate_limiter` before each websocket connect and snapshot request. Share a
+    /// limiter between connections to pace their requests together. No limit by default.
+    pub fn rate_limiter(mut self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Fetch snapshots with `client`, e.g. to set a request timeout or proxy. By default,
+    /// connections share a client that times out requests after 10s.
+    pub fn http_client(mut self, client: Arc<reqwest::Client>) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Returns a stream of the raw websocket text frames received from now on. The stream ends
+    /// immediately unless enabled with `raw_frames`. Subscribe before handing the connection to
+    /// an order book.
+    pub fn subscribe_raw(&self) -> impl Stream<Item = String> {
+        let receiver = self.raw_frames.as_ref().map(|sender| sender.subscribe());
+        futures::stream::unfold(receiver, |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(frame) => return Some((frame, Some(receiver))),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
 }
 
 #[async_trait]
-impl Connection for BinanceConnection {
-    async fn stream(&self) -> Result<ConnectionStream, Error> {
-        let snapshot_url = format!(
-            "https://api.binance.com/api/v3/depth?symbol={}&limit=1000",
-            self.symbol
-        );
-        let stream_url = format!(
-            "wss://stream.binance.com:9443/ws/{}@depth",
-            self.symbol.to_lowercase()
+impl<P: Number> Connection<P> for BinanceConnection<P> {
+    async fn stream(&self) -> Result<ConnectionStream<P>, Error> {
+        let span = tracing::info_span!(
+            "bootstrap",
+            symbol = %self.symbol,
+            venue = "binance",
+            outcome = tracing::field::Empty,
         );
+        let result = self.bootstrap().instrument(span.clone()).await;
+        span.record("outcome", if result.is_ok() { "ok" } else { "error" });
+        result
+    }
+}
+
+impl<P: Number> BinanceConnection<P> {
+    /// Starts the live stream and fetches the snapshot, returning the combined order stream.
+    async fn bootstrap(&self) -> Result<ConnectionStream<P>, Error> {
+        self.check_symbol()?;
+        let stream_url = format!("{}/ws/{}", self.stream_base_url(), self.depth_stream_name());
 
         // Start the stream
-        let mut delta_stream = stream(&stream_url).await?;
+        self.rate_limiter.acquire().await;
+        let delta_stream = stream::<Delta>(
+            &stream_url,
+            self.raw_frames.clone(),
+            self.compressed_frames,
+            self.message_size.clone(),
+        )
+        .await?;
+        self.synchronize(delta_stream).await
+    }
+
+    /// Fetches the snapshot while buffering deltas from the started `delta_stream`, returning the
+    /// stream of the snapshot's orders followed by the deltas'.
+    pub(super) async fn synchronize<S>(
+        &self,
+        mut delta_stream: S,
+    ) -> Result<ConnectionStream<P>, Error>
+    where
+        S: Stream<Item = Result<Delta, Error>> + Unpin + Send + Sync + 'static,
+    {
+        let snapshot_url = self.snapshot_url();
         let mut delta_buffer = VecDeque::new();
 
+        // Fetch the snapshot, retrying failures while continuing to buffer deltas.
+        let mut attempt = 0;
         let snapshot = loop {
-            tokio::select! {
-                delta_result = delta_stream.next() => {
-                    match delta_result {
-                        Some(Ok(delta)) => delta_buffer.push_back(delta),
-                        Some(Err(e)) => {
-                            break Err(Error::from(e));
-                        }
-                        None => {
-                            break Err(Error::Stream(TungsteniteError::ConnectionClosed));
-                        }
-                    }
-                }
-                snapshot_result = snapshot(&snapshot_url) => {
-                    break snapshot_result;
+            let snapshot_future = async {
+                self.rate_limiter.acquire().await;
+                snapshot(&self.client, &snapshot_url, self.snapshot_timeout).await
+            };
+            match buffer_deltas(&mut delta_stream, &mut delta_buffer, snapshot_future).await? {
+                Ok(snapshot) => break snapshot,
+                Err(e) if attempt < self.snapshot_retries => {
+                    attempt += 1;
+                    tracing::warn!(attempt, error = %e, "Snapshot fetch failed");
+                    // Wait at least as long as the exchange asked, if rate limited.
+                    let delay = SNAPSHOT_RETRY_BACKOFF * attempt;
+                    let delay = delay.max(e.retry_after().unwrap_or_default());
+                    let backoff = tokio::time::sleep(delay);
+                    buffer_deltas(&mut delta_stream, &mut delta_buffer, backoff).await?;
                 }
+                Err(e) => return Err(e),
             }
-        }?;
+        };
 
-        // Drop any buffered deltas that predate the snapshot.
+        // Drop any buffered deltas that predate the snapshot. Futures deltas ending at the
+        // snapshot's last update are still applied first.
         let last_updated = snapshot.last_update_id;
+        let futures = self.sequencing == Sequencing::Futures;
         while let Some(delta) = delta_buffer.front() {
-            if delta.last_update <= last_updated {
+            if delta.last_update < last_updated || !futures && delta.last_update == last_updated {
                 delta_buffer.pop_front();
             } else {
                 break;
@@ -75,7 +393,8 @@ impl Connection for BinanceConnection {
 
         // If there is a buffered delta, check the update times.
         if let Some(delta) = delta_buffer.front() {
-            if delta.first_update > last_updated + 1 || delta.last_update < last_updated + 1 {
+            let (first, last) = (delta.first_update, delta.last_update);
+            if !self.sequencing.is_valid_first(last_updated, first, last) {
                 return Err(Error::UnexpectedItem(format!(
                     "Bad update bounds: {}, {:?}",
                     last_updated + 1,
@@ -84,128 +403,814 @@ impl Connection for BinanceConnection {
             }
         }
 
-        // Convert to order stream
-        let snapshot_orders: Vec<Order> = snapshot.try_into()?;
-        let snapshot_stream =
-            futures::stream::iter(snapshot_orders.into_iter().map(|order| Ok(order)));
-
-        // Convert buffered deltas to stream and chain with live stream
-        let buffer_stream =
-            futures::stream::iter(delta_buffer.into_iter().map(|d| Ok::<_, Error>(d)));
-        let delta_stream = buffer_stream.chain(delta_stream);
-        let deltas = delta_stream
-            .map(|result| match result {
-                Ok(delta) => {
-                    let result: Result<Vec<Order>, Error> = delta.try_into();
-                    match result {
-                        Ok(orders) => {
-                            let stream = futures::stream::iter(
-                                orders.into_iter().map(|o| Ok::<_, Error>(o)),
-                            );
-                            Ok(stream)
-                        }
-                        Err(e) => Err(e),
+        let snapshot_orders = snapshot.into_orders(self.invalid_prices)?;
+        Ok(Box::pin(OrderStream {
+            invalid_prices: self.invalid_prices,
+            sequencing: self.sequencing,
+            last_update: last_updated,
+            continuing: false,
+            phase: Phase::Snapshot,
+            snapshot: snapshot_orders.into_iter(),
+            buffered: delta_buffer,
+            live: delta_stream,
+            delta_orders: None,
+        }))
+    }
+
+    /// URL of the depth snapshot REST endpoint.
+    fn snapshot_url(&self) -> String {
+        format!(
+            "{}{}?symbol={}&limit={}",
+            self.rest_url.as_deref().unwrap_or(self.region.rest_url()),
+            self.snapshot_path,
+            self.symbol,
+            self.snapshot_limit
+        )
+    }
+
+    /// Name of the symbol's depth stream, e.g. "btcusdt@depth@100ms".
+    pub(super) fn depth_stream_name(&self) -> String {
+        let speed = match self.update_speed {
+            UpdateSpeed::Ms100 => "@100ms",
+            UpdateSpeed::Ms1000 => "",
+        };
+        format!("{}@depth{}", self.symbol.to_lowercase(), speed)
+    }
+
+    /// Base URL of the websocket streams.
+    fn stream_base_url(&self) -> &str {
+        self.stream_url.as_deref().unwrap_or(self.region.stream_url())
+    }
+
+    pub(super) fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    /// Fails if the symbol is not in Binance's format.
+    pub(super) fn check_symbol(&self) -> Result<(), Error> {
+        if !is_valid_symbol(&self.symbol) {
+            return Err(Error::InvalidSymbol(self.symbol.clone()));
+        }
+        Ok(())
+    }
+
+    /// Connects to the combined websocket stream of the given stream names, at this connection's
+    /// base URL and with its message size limit.
+    pub(super) async fn combined_stream<T: DeserializeOwned>(
+        &self,
+        names: &[String],
+    ) -> Result<impl Stream<Item = Result<T, Error>>, Error> {
+        let url = format!("{}/stream?streams={}", self.stream_base_url(), names.join("/"));
+        self.rate_limiter.acquire().await;
+        stream::<T>(&url, None, self.compressed_frames, self.message_size.clone()).await
+    }
+
+    /// Connects to the symbol's live trade stream. It is independent of the depth stream, and
+    /// is not reconnected if it fails.
+    pub async fn trades(&self) -> Result<TradeStream<P>, Error> {
+        self.check_symbol()?;
+        let url = format!("{}/ws/{}@trade", self.stream_base_url(), self.symbol.to_lowercase());
+        self.rate_limiter.acquire().await;
+        let events =
+            stream::<TradeEvent>(&url, None, self.compressed_frames, self.message_size.clone())
+                .await?;
+        Ok(Box::pin(events.map(|event| event.and_then(Trade::try_from))))
+    }
+}
+
+/// Returns the smallest snapshot limit covering `levels` levels, or the largest limit.
+pub(super) fn snapshot_limit(levels: usize) -> u32 {
+    let levels = u32::try_from(levels).unwrap_or(u32::MAX);
+    SNAPSHOT_LIMITS
+        .into_iter()
+        .find(|&limit| limit >= levels)
+        .unwrap_or(SNAPSHOT_LIMITS[SNAPSHOT_LIMITS.len() - 1])
+}
+
+/// Maximum websocket message size, raised after a message exceeds it.
+#[derive(Debug)]
+struct MessageSizeLimit {
+    current: AtomicUsize,
+    cap: usize,
+}
+
+impl MessageSizeLimit {
+    fn new(size: usize, cap: usize) -> Self {
+        Self { current: AtomicUsize::new(size), cap: cap.max(size) }
+    }
+
+    /// Returns the websocket config for the current limit.
+    fn config(&self) -> WebSocketConfig {
+        let size = self.current.load(Ordering::Relaxed);
+        WebSocketConfig {
+            max_message_size: Some(size),
+            max_frame_size: Some(size),
+            ..Default::default()
+        }
+    }
+
+    /// Raises the limit to fit a message of `size` bytes, at least doubling it, up to the cap.
+    /// Returns the new limit.
+    fn raise(&self, size: usize) -> usize {
+        let current = self.current.load(Ordering::Relaxed);
+        let raised = size.max(current.saturating_mul(2)).min(self.cap).max(current);
+        self.current.store(raised, Ordering::Relaxed);
+        raised
+    }
+}
+
+/// Phase of an `OrderStream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Emitting the snapshot's orders.
+    Snapshot,
+    /// Emitting orders of deltas buffered while the snapshot was fetched.
+    Buffered,
+    /// Emitting orders of live deltas.
+    Live,
+    /// Ended after a fatal error, as the book can no longer be kept in sync.
+    Ended,
+}
+
+/// Stream of orders from the snapshot, then the buffered deltas, then the live deltas. Fails
+/// and ends if a delta does not continue from the previous one.
+struct OrderStream<S, P> {
+    invalid_prices: InvalidPricePolicy,
+    sequencing: Sequencing,
+    /// Last update id of the snapshot or the previous delta.
+    last_update: u64,
+    /// Whether a delta has followed the snapshot.
+    continuing: bool,
+    phase: Phase,
+    snapshot: vec::IntoIter<Order<P>>,
+    buffered: VecDeque<Delta>,
+    live: S,
+    /// Remaining orders of the delta being emitted.
+    delta_orders: Option<DeltaOrders<P>>,
+}
+
+impl<S, P> Stream for OrderStream<S, P>
+where
+    S: Stream<Item = Result<Delta, Error>> + Unpin,
+    P: Number,
+{
+    type Item = Result<Order<P>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(order) = this.delta_orders.as_mut().and_then(Iterator::next) {
+                return Poll::Ready(Some(Ok(order)));
+            }
+
+            let delta = match this.phase {
+                Phase::Snapshot => match this.snapshot.next() {
+                    Some(order) => return Poll::Ready(Some(Ok(order))),
+                    None => {
+                        this.phase = Phase::Buffered;
+                        continue;
                     }
+                },
+                Phase::Buffered => match this.buffered.pop_front() {
+                    Some(delta) => delta,
+                    None => {
+                        this.phase = Phase::Live;
+                        continue;
+                    }
+                },
+                Phase::Live => match ready!(this.live.poll_next_unpin(cx)) {
+                    Some(Ok(delta)) => delta,
+                    Some(Err(e)) => return this.fail(e),
+                    None => return Poll::Ready(None),
+                },
+                Phase::Ended => return Poll::Ready(None),
+            };
+
+            let (first, last) = (delta.first_update, delta.last_update);
+            let continues = if this.continuing {
+                // Futures deltas give the previous delta's last update id, as update ids are not
+                // contiguous between them.
+                match delta.previous_update {
+                    Some(previous) => previous == this.last_update,
+                    None => first == this.last_update + 1,
                 }
-                Err(e) => Err(e),
-            })
-            .try_flatten();
+            } else {
+                this.sequencing.is_valid_first(this.last_update, first, last)
+            };
+            if !continues {
+                let gap = format!("Update gap: {}, {:?}", this.last_update + 1, delta);
+                return this.fail(Error::UnexpectedItem(gap));
+            }
 
-        Ok(Box::pin(snapshot_stream.chain(deltas)))
+            // A delta that fails to parse is not consumed, so the next one leaves a gap and the
+            // book resyncs instead of silently missing its levels.
+            match DeltaOrders::parse(delta, this.invalid_prices) {
+                Ok(orders) => this.delta_orders = Some(orders),
+                Err(e) => return this.fail(e),
+            }
+            this.last_update = last;
+            this.continuing = true;
+        }
+    }
+}
+
+impl<S, P> OrderStream<S, P> {
+    /// Returns an error, ending the stream if it is fatal.
+    fn fail(&mut self, e: Error) -> Poll<Option<Result<Order<P>, Error>>> {
+        if e.is_fatal() {
+            self.phase = Phase::Ended;
+        }
+        Poll::Ready(Some(Err(e)))
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct Snapshot {
+pub(super) struct Snapshot {
     /// last update time in snapshot
     #[serde(rename(deserialize = "lastUpdateId"))]
-    last_update_id: u64,
+    pub(super) last_update_id: u64,
     /// Snapshot bids (price, quantity)
-    bids: Vec<(String, String)>,
+    pub(super) bids: Vec<(String, String)>,
     /// Snapshot asks (price, quantity)
-    asks: Vec<(String, String)>,
+    pub(super) asks: Vec<(String, String)>,
 }
 
-impl TryFrom<Snapshot> for Vec<Order> {
-    type Error = Error;
-
-    fn try_from(value: Snapshot) -> Result<Self, Self::Error> {
-        let mut asks = to_asks(value.asks)?;
-        let bids = to_bids(value.bids)?;
+impl Snapshot {
+    fn into_orders<P: Number>(
+        self,
+        invalid_prices: InvalidPricePolicy,
+    ) -> Result<Vec<Order<P>>, Error> {
+        let mut asks = to_asks(self.asks, invalid_prices)?;
+        let bids = to_bids(self.bids, invalid_prices)?;
         asks.extend(bids);
         Ok(asks)
     }
 }
 
-fn to_asks(raw: Vec<(String, String)>) -> Result<Vec<Order>, Error> {
-    raw.into_iter()
-        .map(|a| Ok(Order::Ask(a.try_into()?)))
-        .collect::<Result<Vec<_>, _>>()
+fn to_asks<P: Number>(
+    raw: Vec<(String, String)>,
+    invalid_prices: InvalidPricePolicy,
+) -> Result<Vec<Order<P>>, Error> {
+    Ok(to_levels(raw, invalid_prices)?.into_iter().map(Order::Ask).collect())
 }
 
-fn to_bids(raw: Vec<(String, String)>) -> Result<Vec<Order>, Error> {
-    raw.into_iter()
-        .map(|a| Ok(Order::Bid(a.try_into()?)))
-        .collect::<Result<Vec<_>, _>>()
+fn to_bids<P: Number>(
+    raw: Vec<(String, String)>,
+    invalid_prices: InvalidPricePolicy,
+) -> Result<Vec<Order<P>>, Error> {
+    Ok(to_levels(raw, invalid_prices)?.into_iter().map(Order::Bid).collect())
 }
 
-impl TryFrom<(String, String)> for OrderDetails {
+/// Parses a frame's levels in ascending price order, keeping only the last level listed at each
+/// price as it supersedes any earlier ones.
+pub(super) fn to_levels<P: Number>(
+    raw: Vec<(String, String)>,
+    invalid_prices: InvalidPricePolicy,
+) -> Result<Vec<OrderDetails<P>>, Error> {
+    let mut levels = BTreeMap::new();
+    for level in raw {
+        if let Some(details) = to_level(level, invalid_prices)? {
+            levels.insert(PriceKey(details.price), details);
+        }
+    }
+    Ok(levels.into_values().collect())
+}
+
+/// Parses a level, returning `None` if it has a non-positive price and the policy drops it.
+fn to_level<P: Number>(
+    raw: (String, String),
+    invalid_prices: InvalidPricePolicy,
+) -> Result<Option<OrderDetails<P>>, Error> {
+    match OrderDetails::try_from(raw) {
+        Ok(details) => Ok(Some(details)),
+        Err(Error::InvalidPrice(price)) if invalid_prices == InvalidPricePolicy::Drop => {
+            tracing::warn!(%price, "Dropping level with invalid price");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+impl<P: Number> TryFrom<(String, String)> for OrderDetails<P> {
     type Error = Error;
 
     fn try_from((p, q): (String, String)) -> Result<Self, Self::Error> {
+        let price = parse_number::<P>(&p)?;
+        if price <= P::default() {
+            return Err(Error::InvalidPrice(p));
+        }
+        let quantity = parse_number::<P>(&q)?;
+        if quantity < P::default() {
+            return Err(Error::UnexpectedItem(format!("Negative quantity {}", q)));
+        }
         Ok(OrderDetails {
-            price: p.parse::<f32>()?,
-            quantity: q.parse::<f32>()?,
+            price,
+            quantity,
+            event_time: None,
         })
     }
 }
 
+/// Parses a number in plain decimal notation, as exchanges send them. Float types would also
+/// accept "NaN", "inf" and exponents overflowing to infinity, which break the book's ordering,
+/// so anything but digits, a sign and a decimal point is rejected first.
+fn parse_number<P: Number>(s: &str) -> Result<P, Error> {
+    let plain = |b: u8| b.is_ascii_digit() || matches!(b, b'.' | b'-' | b'+');
+    if !s.bytes().all(plain) {
+        return Err(Error::ParseNumber(format!("{s}: not a finite number")));
+    }
+    s.parse().map_err(|e| Error::ParseNumber(format!("{s}: {e}")))
+}
+
+/// A depth update. Its event type, always "depthUpdate", and symbol, always the requested one,
+/// are not read.
 #[derive(Debug, Deserialize)]
-struct Delta {
-    /// event type (always "depthUpdate")
-    #[serde(skip)]
-    e: String,
+pub(super) struct Delta {
     /// event timestamp
     #[serde(rename(deserialize = "E"))]
     event_time: u64,
-    /// symbol (always requested symbol)
-    #[serde(skip)]
-    s: String,
     /// First update id
     #[serde(rename(deserialize = "U"))]
     first_update: u64,
     /// Last update id
     #[serde(rename(deserialize = "u"))]
     last_update: u64,
+    /// Last update id of the previous delta (futures only)
+    #[serde(rename(deserialize = "pu"), default)]
+    previous_update: Option<u64>,
     /// bids
     b: Vec<(String, String)>,
     /// asks
     a: Vec<(String, String)>,
 }
 
-impl TryFrom<Delta> for Vec<Order> {
-    type Error = Error;
+/// Orders from a single delta. Deltas of up to two levels, which are most of them, are held
+/// inline rather than allocated.
+enum DeltaOrders<P> {
+    Inline(Flatten<array::IntoIter<Option<Order<P>>, 2>>),
+    Allocated(vec::IntoIter<Order<P>>),
+}
 
-    fn try_from(value: Delta) -> Result<Self, Self::Error> {
-        let mut asks = to_asks(value.a)?;
-        let bids = to_bids(value.b)?;
+impl<P> Iterator for DeltaOrders<P> {
+    type Item = Order<P>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            DeltaOrders::Inline(orders) => orders.next(),
+            DeltaOrders::Allocated(orders) => orders.next(),
+        }
+    }
+}
+
+impl<P: Number> DeltaOrders<P> {
+    /// Converts a delta into its orders, handling non-positive prices per `invalid_prices`.
+    fn parse(value: Delta, invalid_prices: InvalidPricePolicy) -> Result<Self, Error> {
+        let event_time = Some(ExchangeTime(value.event_time));
+        if value.a.len() + value.b.len() <= 2 {
+            let asks = value.a.into_iter().map(|level| (Order::Ask as fn(_) -> _, level));
+            let bids = value.b.into_iter().map(|level| (Order::Bid as fn(_) -> _, level));
+            let mut orders = [None, None];
+            for (slot, (side, level)) in orders.iter_mut().zip(asks.chain(bids)) {
+                let Some(mut details) = to_level(level, invalid_prices)? else {
+                    continue;
+                };
+                details.event_time = event_time;
+                *slot = Some(side(details));
+            }
+
+            // Keep only the last of two levels at the same price.
+            if let [Some(Order::Ask(first)), Some(Order::Ask(second))]
+            | [Some(Order::Bid(first)), Some(Order::Bid(second))] = &orders
+            {
+                if first.price == second.price {
+                    orders[0] = None;
+                }
+            }
+            return Ok(DeltaOrders::Inline(orders.into_iter().flatten()));
+        }
+
+        let mut asks = to_asks(value.a, invalid_prices)?;
+        let bids = to_bids(value.b, invalid_prices)?;
         asks.extend(bids);
-        Ok(asks)
+        for order in asks.iter_mut() {
+            let (Order::Bid(details) | Order::Ask(details)) = order;
+            details.event_time = event_time;
+        }
+        Ok(DeltaOrders::Allocated(asks.into_iter()))
+    }
+}
+
+/// A message of the trade stream.
+#[derive(Debug, Deserialize)]
+struct TradeEvent {
+    /// Trade time
+    #[serde(rename(deserialize = "T"))]
+    trade_time: u64,
+    /// Price
+    p: String,
+    /// Quantity
+    q: String,
+    /// Whether the buyer is the market maker
+    m: bool,
+}
+
+impl<P: Number> TryFrom<TradeEvent> for Trade<P> {
+    type Error = Error;
+
+    fn try_from(value: TradeEvent) -> Result<Self, Self::Error> {
+        Ok(Trade {
+            price: parse_number(&value.p)?,
+            quantity: parse_number(&value.q)?,
+            timestamp: ExchangeTime(value.trade_time),
+            is_buyer_maker: value.m,
+        })
+    }
+}
+
+/// Parses a Binance depth update message into the orders it contains.
+pub fn parse_depth_update(text: &str) -> Result<impl Iterator<Item = Order>, Error> {
+    DeltaOrders::parse(serde_json::from_str::<Delta>(text)?, InvalidPricePolicy::Reject)
+}
+
+/// Drives `future` to completion while buffering deltas from the live stream. Fails if the
+/// stream errors, closes or overflows the buffer first.
+async fn buffer_deltas<S, F>(
+    delta_stream: &mut S,
+    delta_buffer: &mut VecDeque<Delta>,
+    future: F,
+) -> Result<F::Output, Error>
+where
+    S: Stream<Item = Result<Delta, Error>> + Unpin,
+    F: Future,
+{
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            delta_result = delta_stream.next() => {
+                match delta_result {
+                    Some(Ok(delta)) if delta_buffer.len() < MAX_BUFFERED_DELTAS => {
+                        delta_buffer.push_back(delta)
+                    }
+                    Some(Ok(_)) => {
+                        return Err(Error::UnexpectedItem("Delta buffer overflow".to_owned()));
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(Error::from(TungsteniteError::ConnectionClosed)),
+                }
+            }
+            output = &mut future => return Ok(output),
+        }
     }
 }
 
-async fn snapshot(url: &str) -> Result<Snapshot, Error> {
-    Ok(reqwest::get(url).await?.json::<Snapshot>().await?)
+/// Decompresses a gzip-compressed frame into text.
+fn inflate(data: &[u8]) -> Result<String, Error> {
+    let mut text = String::new();
+    GzDecoder::new(data)
+        .read_to_string(&mut text)
+        .map_err(|e| Error::UnexpectedItem(format!("Invalid compressed frame: {e}")))?;
+    Ok(text)
 }
 
-async fn stream(url: &str) -> Result<impl Stream<Item = Result<Delta, Error>>, Error> {
-    let (stream, _) = tokio_tungstenite::connect_async(url).await?;
+/// Returns the client shared by default between connections, creating it on first use.
+pub(super) fn shared_client() -> Arc<reqwest::Client> {
+    static CLIENT: OnceLock<Arc<reqwest::Client>> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            let client = reqwest::Client::builder().timeout(DEFAULT_SNAPSHOT_TIMEOUT).build();
+            Arc::new(client.expect("Failed to create the HTTP client"))
+        })
+        .clone()
+}
 
-    Ok(stream.map(|result| match result {
+pub(super) async fn snapshot(
+    client: &reqwest::Client,
+    url: &str,
+    timeout: Option<Duration>,
+) -> Result<Snapshot, Error> {
+    // A request timeout overrides the client's.
+    let mut request = client.get(url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let response = request.send().await?;
+    if let StatusCode::TOO_MANY_REQUESTS | StatusCode::IM_A_TEAPOT = response.status() {
+        // Retry-After is in seconds.
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        if let Some(retry_after) = retry_after {
+            return Err(Error::RateLimited { retry_after: Duration::from_secs(retry_after) });
+        }
+    }
+    Ok(response
+        .error_for_status()?
+        .json::<Snapshot>()
+        .await?)
+}
+
+/// Connects to a websocket stream of JSON messages of type `T`, in text frames or, if
+/// `compressed`, gzip-compressed binary frames.
+async fn stream<T: DeserializeOwned>(
+    url: &str,
+    raw_frames: Option<broadcast::Sender<String>>,
+    compressed: bool,
+    message_size: Arc<MessageSizeLimit>,
+) -> Result<impl Stream<Item = Result<T, Error>>, Error> {
+    let config = message_size.config();
+    let (stream, _) = tokio_tungstenite::connect_async_with_config(url, Some(config), false).await?;
+
+    let parse = move |s: String| {
+        if let Some(raw_frames) = &raw_frames {
+            // No subscribers is not an error.
+            let _ = raw_frames.send(s.clone());
+        }
+        serde_json::from_str::<T>(&s).map_err(Error::from)
+    };
+    let stream = stream.filter(|result| {
+        // Answered by tungstenite.
+        let control = matches!(result, Ok(Message::Ping(_) | Message::Pong(_)));
+        futures::future::ready(!control)
+    });
+    Ok(stream.map(move |result| match result {
         Ok(msg) => match msg {
-            Message::Text(s) => serde_json::from_str::<Delta>(&s).map_err(Error::from),
+            Message::Text(s) => parse(s),
+            Message::Binary(data) if compressed => inflate(&data).and_then(&parse),
+            Message::Close(frame) => Err(match frame {
+                Some(frame) => Error::StreamClosed {
+                    code: frame.code.into(),
+                    reason: frame.reason.into_owned(),
+                },
+                // 1005, no status received.
+                None => Error::StreamClosed {
+                    code: CloseCode::Status.into(),
+                    reason: String::new(),
+                },
+            }),
             other => Err(Error::UnexpectedItem(format!("{:?}", other))),
         },
-        Err(e) => Err(Error::Stream(e)),
+        Err(e) => {
+            match &e {
+                TungsteniteError::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+                    let raised = message_size.raise(*size);
+                    tracing::warn!(size, max_size, raised, "Websocket message exceeds size limit");
+                }
+                TungsteniteError::Protocol(protocol) => {
+                    tracing::warn!(%protocol, "Websocket protocol violation");
+                }
+                _ => {}
+            }
+            Err(Error::from(e))
+        }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A delta covering `first..=last`, with a bid level.
+    fn delta(first: u64, last: u64, bid: &str) -> Delta {
+        let text = format!(
+            r#"{{"e":"depthUpdate","E":1,"s":"X","U":{},"u":{},"b":[["{}","1"]],"a":[]}}"#,
+            first, last, bid
+        );
+        serde_json::from_str(&text).unwrap()
+    }
+
+    /// An order stream from a snapshot up to update 10 with a bid at 100, then the buffered and
+    /// live deltas.
+    fn order_stream(
+        buffered: Vec<Delta>,
+        live: Vec<Result<Delta, Error>>,
+    ) -> OrderStream<impl Stream<Item = Result<Delta, Error>> + Unpin, f64> {
+        let snapshot: Snapshot =
+            serde_json::from_str(r#"{"lastUpdateId":10,"bids":[["100","1"]],"asks":[]}"#).unwrap();
+        OrderStream {
+            invalid_prices: InvalidPricePolicy::Reject,
+            sequencing: Sequencing::Overlapping,
+            last_update: snapshot.last_update_id,
+            continuing: false,
+            phase: Phase::Snapshot,
+            snapshot: snapshot.into_orders(InvalidPricePolicy::Reject).unwrap().into_iter(),
+            buffered: buffered.into(),
+            live: futures::stream::iter(live),
+            delta_orders: None,
+        }
+    }
+
+    /// Bid prices of the stream's orders until it ends, with `Err(fatal)` for errors.
+    fn bids(stream: impl Stream<Item = Result<Order, Error>>) -> Vec<Result<f64, bool>> {
+        let items = futures::executor::block_on(stream.collect::<Vec<_>>());
+        items
+            .into_iter()
+            .map(|item| match item {
+                Ok(Order::Bid(details)) => Ok(details.price),
+                Ok(Order::Ask(_)) => panic!("unexpected ask"),
+                Err(e) => Err(e.is_fatal()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn default_urls() {
+        let connection = BinanceConnection::new("BTCUSDT");
+        assert_eq!(
+            connection.snapshot_url(),
+            "https://api.binance.com/api/v3/depth?symbol=BTCUSDT&limit=1000"
+        );
+        assert_eq!(connection.depth_stream_name(), "btcusdt@depth");
+        assert_eq!(connection.stream_base_url(), "wss://stream.binance.com:9443");
+    }
+
+    #[test]
+    fn configured_urls() {
+        let connection = BinanceConnection::new("ETHBTC")
+            .tracked_depth(100)
+            .update_speed(UpdateSpeed::Ms100);
+        assert_eq!(
+            connection.snapshot_url(),
+            "https://api.binance.com/api/v3/depth?symbol=ETHBTC&limit=100"
+        );
+        assert_eq!(connection.depth_stream_name(), "ethbtc@depth@100ms");
+    }
+
+    /// Side, price and quantity of each order.
+    fn levels(orders: impl Iterator<Item = Order>) -> Vec<(&'static str, f64, f64)> {
+        orders
+            .map(|order| match order {
+                Order::Bid(details) => ("bid", details.price, details.quantity),
+                Order::Ask(details) => ("ask", details.price, details.quantity),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn last_level_at_a_price_wins() {
+        // Two levels, parsed inline.
+        let frame = r#"{"e":"depthUpdate","E":5,"s":"X","U":1,"u":2,
+            "b":[["1.5","2"],["1.5","0"]],"a":[]}"#;
+        assert_eq!(levels(parse_depth_update(frame).unwrap()), [("bid", 1.5, 0.0)]);
+
+        let frame = r#"{"e":"depthUpdate","E":5,"s":"X","U":1,"u":2,
+            "b":[["1.5","2"],["1.4","1"],["1.5","3"]],"a":[["2","1"],["2","4"]]}"#;
+        assert_eq!(
+            levels(parse_depth_update(frame).unwrap()),
+            [("ask", 2.0, 4.0), ("bid", 1.4, 1.0), ("bid", 1.5, 3.0)]
+        );
+    }
+
+    #[test]
+    fn streams_the_snapshot_then_buffered_then_live_deltas() {
+        let stream = order_stream(vec![delta(9, 11, "99")], vec![Ok(delta(12, 12, "98"))]);
+        assert_eq!(bids(stream), [Ok(100.0), Ok(99.0), Ok(98.0)]);
+
+        // Straight from the snapshot to live deltas.
+        let stream = order_stream(Vec::new(), vec![Ok(delta(11, 11, "99"))]);
+        assert_eq!(bids(stream), [Ok(100.0), Ok(99.0)]);
+    }
+
+    #[test]
+    fn ends_after_a_fatal_error() {
+        let closed = Error::StreamClosed { code: 1000, reason: String::new() };
+        let stream = order_stream(Vec::new(), vec![Err(closed), Ok(delta(11, 11, "99"))]);
+        assert_eq!(bids(stream), [Ok(100.0), Err(true)]);
+    }
+
+    #[test]
+    fn continues_after_a_transient_error() {
+        let invalid = Error::ParseJson(serde_json::from_str::<Delta>("{}").unwrap_err());
+        let stream = order_stream(Vec::new(), vec![Err(invalid), Ok(delta(11, 11, "99"))]);
+        assert_eq!(bids(stream), [Ok(100.0), Err(false), Ok(99.0)]);
+    }
+
+    #[test]
+    fn snapshot_limit_covers_the_tracked_depth() {
+        assert_eq!(snapshot_limit(0), 5);
+        assert_eq!(snapshot_limit(5), 5);
+        assert_eq!(snapshot_limit(6), 10);
+        assert_eq!(snapshot_limit(30), 50);
+        assert_eq!(snapshot_limit(500), 500);
+        assert_eq!(snapshot_limit(501), 1000);
+        assert_eq!(snapshot_limit(5000), 5000);
+        // More than the largest limit gets the largest.
+        assert_eq!(snapshot_limit(10_000), 5000);
+        assert_eq!(snapshot_limit(usize::MAX), 5000);
+    }
+
+    #[test]
+    fn non_positive_prices_are_rejected_or_dropped() {
+        let frame = |bids: &str| -> Delta {
+            let text = format!(
+                r#"{{"e":"depthUpdate","E":1,"s":"X","U":1,"u":1,"b":{},"a":[]}}"#,
+                bids
+            );
+            serde_json::from_str(&text).unwrap()
+        };
+        let cases = [
+            (r#"[["0","1"],["99","1"]]"#, vec![99.0]),
+            (r#"[["-1","1"],["99","1"],["98","1"]]"#, vec![98.0, 99.0]),
+        ];
+        for (bids, valid) in cases {
+            let rejected = DeltaOrders::<f64>::parse(frame(bids), InvalidPricePolicy::Reject);
+            assert!(matches!(rejected, Err(Error::InvalidPrice(_))));
+
+            let dropped = DeltaOrders::<f64>::parse(frame(bids), InvalidPricePolicy::Drop);
+            let prices: Vec<_> = levels(dropped.unwrap()).iter().map(|level| level.1).collect();
+            assert_eq!(prices, valid);
+        }
+    }
+
+    #[test]
+    fn prices_round_trip_without_loss() {
+        let level = |price: &str| {
+            OrderDetails::<f64>::try_from((price.to_owned(), "0.00012345".to_owned())).unwrap()
+        };
+        let details = level("64123.45");
+        assert_eq!(details.price, 64123.45);
+        assert_eq!(details.price.to_string(), "64123.45");
+        assert_eq!(details.quantity.to_string(), "0.00012345");
+        // Adjacent ticks stay distinct levels.
+        assert_ne!(level("64123.45").price, level("64123.46").price);
+    }
+
+    #[test]
+    fn non_finite_and_negative_numbers_are_rejected() {
+        let parse = |price: &str, quantity: &str| {
+            OrderDetails::<f32>::try_from((price.to_owned(), quantity.to_owned()))
+        };
+        for number in ["NaN", "inf", "-inf", "1e39"] {
+            assert!(matches!(parse(number, "1.0"), Err(Error::ParseNumber(_))), "{number}");
+            assert!(matches!(parse("1.0", number), Err(Error::ParseNumber(_))), "{number}");
+        }
+        assert!(matches!(parse("-1.0", "1.0"), Err(Error::InvalidPrice(_))));
+        assert!(matches!(parse("1.0", "-1.0"), Err(Error::UnexpectedItem(_))));
+        assert!(parse("1.0", "0").is_ok());
+    }
+
+    #[test]
+    fn trade_event_parses_into_a_trade() {
+        let payload = r#"{"e":"trade","E":1672515782136,"s":"BNBBTC","t":12345,"p":"0.001",
+            "q":"100","T":1672515782136,"m":true,"M":true}"#;
+        let event: TradeEvent = serde_json::from_str(payload).unwrap();
+        let trade = Trade::<f64>::try_from(event).unwrap();
+        assert_eq!(
+            trade,
+            Trade {
+                price: 0.001,
+                quantity: 100.0,
+                timestamp: ExchangeTime(1672515782136),
+                is_buyer_maker: true,
+            }
+        );
+    }
+
+    #[test]
+    fn symbols_in_binance_format() {
+        assert!(is_valid_symbol("BTCUSDT"));
+        assert!(is_valid_symbol("1000SATSUSDT"));
+        for symbol in ["", "btcusdt", "BTC-USD", "BTC/USDT", "BTC USDT"] {
+            assert!(!is_valid_symbol(symbol), "{symbol}");
+        }
+        let connection = BinanceConnection::new("btcusdt").region(BinanceRegion::Us);
+        assert!(matches!(connection.check_symbol(), Err(Error::InvalidSymbol(_))));
+    }
+
+    #[test]
+    fn in_order_deltas_continue() {
+        let live = vec![Ok(delta(11, 12, "99")), Ok(delta(13, 13, "98")), Ok(delta(14, 20, "97"))];
+        let stream = order_stream(Vec::new(), live);
+        assert_eq!(bids(stream), [Ok(100.0), Ok(99.0), Ok(98.0), Ok(97.0)]);
+    }
+
+    #[test]
+    fn a_gap_fails_and_ends_the_stream() {
+        let live = vec![Ok(delta(11, 12, "99")), Ok(delta(14, 14, "98")), Ok(delta(15, 15, "97"))];
+        let stream = order_stream(Vec::new(), live);
+        assert_eq!(bids(stream), [Ok(100.0), Ok(99.0), Err(true)]);
+
+        // Between the buffered and live deltas.
+        let stream = order_stream(vec![delta(11, 11, "99")], vec![Ok(delta(13, 13, "98"))]);
+        assert_eq!(bids(stream), [Ok(100.0), Ok(99.0), Err(true)]);
+    }
+
+    #[test]
+    fn testnet_urls() {
+        let connection = BinanceConnection::testnet("BTCUSDT");
+        assert_eq!(
+            connection.snapshot_url(),
+            "https://testnet.binance.vision/api/v3/depth?symbol=BTCUSDT&limit=1000"
+        );
+        assert_eq!(connection.stream_base_url(), "wss://stream.testnet.binance.vision");
+
+        let connection =
+            BinanceConnection::new("BTCUSDT").base_urls("http://localhost:8080", "ws://localhost");
+        assert_eq!(
+            connection.snapshot_url(),
+            "http://localhost:8080/api/v3/depth?symbol=BTCUSDT&limit=1000"
+        );
+        assert_eq!(connection.stream_base_url(), "ws://localhost");
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+use super::binance::Delta;
+use super::BinanceConnection;
+use super::BinanceRegion;
+use super::Error;
+use super::Number;
+use super::RateLimiter;
+use super::SymbolOrder;
+use super::SymbolOrderStream;
+use super::UpdateSpeed;
+
+/// A connection to Binance for several symbols over a single combined websocket stream, parsing
+/// prices and quantities as `P`. Each symbol's book is synced from its own snapshot, and its
+/// orders are tagged with the symbol.
+pub struct BinanceCombinedConnection<P = f64> {
+    /// A connection per symbol, holding its settings.
+    connections: Vec<BinanceConnection<P>>,
+}
+
+impl BinanceCombinedConnection {
+    pub fn new(symbols: Vec<String>) -> Self {
+        Self {
+            connections: symbols.iter().map(|symbol| BinanceConnection::new(symbol)).collect(),
+        }
+    }
+}
+
+impl<P> BinanceCombinedConnection<P> {
+    /// Parse prices and quantities as `Q` rather than `f64`, e.g. a decimal type.
+    pub fn number_type<Q>(self) -> BinanceCombinedConnection<Q> {
+        BinanceCombinedConnection {
+            connections: self.connections.into_iter().map(|c| c.number_type()).collect(),
+        }
+    }
+
+    /// Connect to the given regional exchange, as for `BinanceConnection::region`.
+    pub fn region(self, region: BinanceRegion) -> Self {
+        self.map(|c| c.region(region))
+    }
+
+    /// Connect to the given base URLs of the REST API and websocket streams instead of the
+    /// region's, as for `BinanceConnection::base_urls`.
+    pub fn base_urls(self, rest_url: &str, stream_url: &str) -> Self {
+        self.map(|c| c.base_urls(rest_url, stream_url))
+    }
+
+    /// Fetch snapshots deep enough to track `levels` levels per side, as for
+    /// `BinanceConnection::tracked_depth`.
+    pub fn tracked_depth(self, levels: usize) -> Self {
+        self.map(|c| c.tracked_depth(levels))
+    }
+
+    /// Set the interval at which depth updates are pushed, every second by default.
+    pub fn update_speed(self, update_speed: UpdateSpeed) -> Self {
+        self.map(|c| c.update_speed(update_speed))
+    }
+
+    /// Retry a failed snapshot fetch up to `retries` times per symbol.
+    pub fn snapshot_retries(self, retries: u32) -> Self {
+        self.map(|c| c.snapshot_retries(retries))
+    }
+
+    /// Wait for `rate_limiter` before the websocket connect and each snapshot request.
+    pub fn rate_limiter(self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.map(|c| c.rate_limiter(rate_limiter.clone()))
+    }
+
+    /// Fetch snapshots with `client`, e.g. to set a request timeout or proxy.
+    pub fn http_client(self, client: Arc<reqwest::Client>) -> Self {
+        self.map(|c| c.http_client(client.clone()))
+    }
+
+    fn map(mut self, f: impl Fn(BinanceConnection<P>) -> BinanceConnection<P>) -> Self {
+        self.connections = self.connections.into_iter().map(f).collect();
+        self
+    }
+}
+
+impl<P: Number> BinanceCombinedConnection<P> {
+    /// Connects to the combined stream and syncs each symbol, returning the orders of all of
+    /// them. A fatal error on any symbol ends the stream, so that all are resynced together on
+    /// the next connection.
+    pub async fn stream(&self) -> Result<SymbolOrderStream<P>, Error> {
+        let Some(first) = self.connections.first() else {
+            return Err(Error::InvalidSymbol(String::new()));
+        };
+        for connection in &self.connections {
+            connection.check_symbol()?;
+        }
+        let names: Vec<_> = self.connections.iter().map(|c| c.depth_stream_name()).collect();
+        let envelopes = first.combined_stream::<Envelope>(&names).await?;
+
+        // Route each symbol's deltas to its own stream, keyed by the symbol's part of the name.
+        let mut senders = HashMap::new();
+        let mut receivers = Vec::new();
+        for name in &names {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let symbol = name.split('@').next().unwrap_or_default().to_owned();
+            senders.insert(symbol, sender);
+            receivers.push(receiver);
+        }
+        let first_symbol = names[0].split('@').next().unwrap_or_default().to_owned();
+        tokio::spawn(demultiplex(Box::pin(envelopes), senders, first_symbol));
+
+        let synced = self.connections.iter().zip(receivers).map(|(connection, mut receiver)| {
+            let deltas = futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+            let symbol: Arc<str> = connection.symbol().into();
+            let span = tracing::info_span!("bootstrap", %symbol, venue = "binance");
+            async move {
+                let orders = connection.synchronize(deltas).instrument(span).await?;
+                Ok::<_, Error>(orders.map(move |order| {
+                    order.map(|order| SymbolOrder { symbol: symbol.clone(), order })
+                }))
+            }
+        });
+        let streams = futures::future::try_join_all(synced).await?;
+
+        let orders = futures::stream::select_all(streams).scan(false, |ended, item| {
+            if *ended {
+                return futures::future::ready(None);
+            }
+            if let Err(e) = &item {
+                *ended = e.is_fatal();
+            }
+            futures::future::ready(Some(item))
+        });
+        Ok(Box::pin(orders))
+    }
+}
+
+/// A message of a combined stream, wrapping a message of the named stream.
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    /// Stream name, e.g. "btcusdt@depth"
+    stream: String,
+    data: Delta,
+}
+
+/// Forwards each delta of the combined stream to its symbol's sender, until the stream ends or
+/// a receiver is dropped. Errors cannot be attributed to a symbol, and are forwarded to the
+/// first symbol's.
+async fn demultiplex<S>(
+    mut envelopes: S,
+    senders: HashMap<String, mpsc::UnboundedSender<Result<Delta, Error>>>,
+    first_symbol: String,
+) where
+    S: Stream<Item = Result<Envelope, Error>> + Unpin,
+{
+    while let Some(envelope) = envelopes.next().await {
+        let (symbol, delta) = match envelope {
+            Ok(envelope) => {
+                let symbol = envelope.stream.split('@').next().unwrap_or_default().to_owned();
+                (symbol, Ok(envelope.data))
+            }
+            Err(e) => (first_symbol.clone(), Err(e)),
+        };
+        let Some(sender) = senders.get(&symbol) else {
+            tracing::warn!(%symbol, "Skipping message of unexpected stream");
+            continue;
+        };
+        if sender.send(delta).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(stream: &str) -> Envelope {
+        let text = format!(
+            r#"{{"stream":"{}","data":{{"e":"depthUpdate","E":1,"s":"X","U":5,"u":6,
+                "b":[["100.0","1.0"]],"a":[]}}}}"#,
+            stream
+        );
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn envelope_deserializes() {
+        assert_eq!(envelope("btcusdt@depth@100ms").stream, "btcusdt@depth@100ms");
+        let missing_data = serde_json::from_str::<Envelope>(r#"{"stream":"btcusdt@depth"}"#);
+        assert!(missing_data.is_err());
+    }
+
+    #[tokio::test]
+    async fn deltas_are_routed_by_symbol() {
+        let (btc, mut btc_deltas) = mpsc::unbounded_channel();
+        let (eth, mut eth_deltas) = mpsc::unbounded_channel();
+        let senders = HashMap::from([("btcusdt".to_owned(), btc), ("ethusdt".to_owned(), eth)]);
+        let envelopes = futures::stream::iter([
+            Ok(envelope("btcusdt@depth")),
+            Ok(envelope("ethusdt@depth")),
+            Ok(envelope("solusdt@depth")),
+            Err(Error::UnexpectedItem("closed".to_owned())),
+            Ok(envelope("ethusdt@depth")),
+        ]);
+        demultiplex(envelopes, senders, "btcusdt".to_owned()).await;
+
+        // Errors go to the first symbol, and unexpected streams are skipped.
+        assert!(matches!(btc_deltas.recv().await, Some(Ok(_))));
+        assert!(matches!(btc_deltas.recv().await, Some(Err(Error::UnexpectedItem(_)))));
+        assert!(btc_deltas.recv().await.is_none());
+        assert!(matches!(eth_deltas.recv().await, Some(Ok(_))));
+        assert!(matches!(eth_deltas.recv().await, Some(Ok(_))));
+        assert!(eth_deltas.recv().await.is_none());
+    }
+}
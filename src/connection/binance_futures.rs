@@ -0,0 +1,100 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use super::BinanceConnection;
+use super::Connection;
+use super::ConnectionStream;
+use super::Error;
+use super::Number;
+use super::RateLimiter;
+use super::Sequencing;
+use super::UpdateSpeed;
+
+/// Base URL of the USD-M futures REST API.
+const FUTURES_REST_URL: &str = "https://fapi.binance.com";
+
+/// Base URL of the USD-M futures websocket streams.
+const FUTURES_STREAM_URL: &str = "wss://fstream.binance.com";
+
+/// Path of the USD-M futures depth snapshot REST endpoint.
+const FUTURES_SNAPSHOT_PATH: &str = "/fapi/v1/depth";
+
+/// A connection to Binance USD-M futures for the specified symbol, parsing prices and quantities
+/// as `P`. Deltas are checked for continuity by the previous update id they carry, and a gap
+/// fails the stream so that the book is resynced.
+pub struct BinanceFuturesConnection<P = f64> {
+    connection: BinanceConnection<P>,
+}
+
+impl BinanceFuturesConnection {
+    pub fn new(symbol: &str) -> Self {
+        Self {
+            connection: BinanceConnection::new(symbol)
+                .base_urls(FUTURES_REST_URL, FUTURES_STREAM_URL)
+                .snapshot_path(FUTURES_SNAPSHOT_PATH)
+                .sequencing(Sequencing::Futures),
+        }
+    }
+}
+
+impl<P> BinanceFuturesConnection<P> {
+    /// Parse prices and quantities as `Q` rather than `f64`, e.g. a decimal type.
+    pub fn number_type<Q>(self) -> BinanceFuturesConnection<Q> {
+        BinanceFuturesConnection { connection: self.connection.number_type() }
+    }
+
+    /// Connect to the given base URLs of the REST API and websocket streams instead of the
+    /// exchange's, e.g. "https://testnet.binancefuture.com" and "wss://fstream.binancefuture.com".
+    pub fn base_urls(mut self, rest_url: &str, stream_url: &str) -> Self {
+        self.connection = self.connection.base_urls(rest_url, stream_url);
+        self
+    }
+
+    /// Fetch a snapshot deep enough to track `levels` levels per side, as for
+    /// `BinanceConnection::tracked_depth`. The futures REST API accepts limits up to 1000.
+    pub fn tracked_depth(mut self, levels: usize) -> Self {
+        self.connection = self.connection.tracked_depth(levels.min(1000));
+        self
+    }
+
+    /// Set the interval at which depth updates are pushed. `UpdateSpeed::Ms1000`, the default,
+    /// selects the futures stream's default of every 250ms.
+    pub fn update_speed(mut self, update_speed: UpdateSpeed) -> Self {
+        self.connection = self.connection.update_speed(update_speed);
+        self
+    }
+
+    /// Retry a failed snapshot fetch up to `retries` times, keeping the websocket stream open,
+    /// before failing the connection.
+    pub fn snapshot_retries(mut self, retries: u32) -> Self {
+        self.connection = self.connection.snapshot_retries(retries);
+        self
+    }
+
+    /// Fail a snapshot request that has not completed within `timeout`, overriding the client's.
+    pub fn snapshot_timeout(mut self, timeout: Duration) -> Self {
+        self.connection = self.connection.snapshot_timeout(timeout);
+        self
+    }
+
+    /// Wait for `rate_limiter` before each websocket connect and snapshot request.
+    pub fn rate_limiter(mut self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.connection = self.connection.rate_limiter(rate_limiter);
+        self
+    }
+
+    /// Fetch snapshots with `client`, e.g. to set a request timeout or proxy.
+    pub fn http_client(mut self, client: Arc<reqwest::Client>) -> Self {
+        self.connection = self.connection.http_client(client);
+        self
+    }
+}
+
+#[async_trait]
+impl<P: Number> Connection<P> for BinanceFuturesConnection<P> {
+    async fn stream(&self) -> Result<ConnectionStream<P>, Error> {
+        self.connection.stream().await
+    }
+}
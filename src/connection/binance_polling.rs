@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+
+use super::binance::is_valid_symbol;
+use super::binance::shared_client;
+use super::binance::snapshot;
+use super::binance::snapshot_limit;
+use super::binance::to_levels;
+use super::binance::Snapshot;
+use super::BinanceRegion;
+use super::Connection;
+use super::ConnectionStream;
+use super::Error;
+use super::InvalidPricePolicy;
+use super::NoRateLimit;
+use super::Order;
+use super::OrderDetails;
+use super::RateLimiter;
+
+/// Default interval between snapshot requests.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection to Binance for the specified symbol that polls the REST depth snapshot instead
+/// of streaming deltas over a websocket, for networks that block websockets. Each snapshot is
+/// compared with the previous one, and the changed levels are emitted, with a quantity of zero
+/// for levels that are no longer in it.
+pub struct BinancePollingConnection {
+    symbol: String,
+    region: BinanceRegion,
+    rest_url: Option<String>,
+    snapshot_limit: u32,
+    interval: Duration,
+    rate_limiter: Arc<dyn RateLimiter>,
+    client: Arc<reqwest::Client>,
+}
+
+impl BinancePollingConnection {
+    pub fn new(symbol: &str) -> Self {
+        Self {
+            symbol: symbol.to_owned(),
+            region: BinanceRegion::Global,
+            rest_url: None,
+            snapshot_limit: 1000,
+            interval: DEFAULT_POLL_INTERVAL,
+            rate_limiter: Arc::new(NoRateLimit),
+            client: shared_client(),
+        }
+    }
+
+    /// Connect to the given regional exchange, `BinanceRegion::Global` by default.
+    pub fn region(mut self, region: BinanceRegion) -> Self {
+        self.region = region;
+        self
+    }
+
+    /// Connect to the given base URL of the REST API instead of the region's, e.g.
+    /// "https://testnet.binance.vision".
+    pub fn base_url(mut self, rest_url: &str) -> Self {
+        self.rest_url = Some(rest_url.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Fetch snapshots deep enough to track `levels` levels per side, as for
+    /// `BinanceConnection::tracked_depth`. Levels beyond the snapshot depth are removed.
+    pub fn tracked_depth(mut self, levels: usize) -> Self {
+        self.snapshot_limit = snapshot_limit(levels);
+        self
+    }
+
+    /// Set the interval between snapshot requests, every second by default. Each request has a
+    /// weight against the REST API's rate limit that grows with the snapshot depth.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Wait for `rate_limiter` before each snapshot request, e.g. a limiter shared with other
+    /// connections, as for `BinanceConnection::rate_limiter`. No limit by default.
+    pub fn rate_limiter(mut self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Fetch snapshots with `client`, e.g. to set a request timeout or proxy.
+    pub fn http_client(mut self, client: Arc<reqwest::Client>) -> Self {
+        self.client = client;
+        self
+    }
+}
+
+#[async_trait]
+impl Connection for BinancePollingConnection {
+    async fn stream(&self) -> Result<ConnectionStream, Error> {
+        if !is_valid_symbol(&self.symbol) {
+            return Err(Error::InvalidSymbol(self.symbol.clone()));
+        }
+        let url = format!(
+            "{}/api/v3/depth?symbol={}&limit={}",
+            self.rest_url.as_deref().unwrap_or(self.region.rest_url()),
+            self.symbol,
+            self.snapshot_limit
+        );
+
+        // Fetch the first snapshot now, so that connecting fails if it cannot be fetched.
+        let client = self.client.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let mut levels = PolledLevels::default();
+        rate_limiter.acquire().await;
+        let first = snapshot(&client, &url, None).await?;
+        let first = levels.update(first)?;
+
+        // Poll from a task, as the rate limiter's futures cannot be held by a `Sync` stream.
+        // The task waits for each snapshot's orders to be taken before polling again, and stops
+        // once the stream is dropped.
+        let interval = self.interval;
+        let (sender, mut receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sender.closed() => return,
+                    _ = tokio::time::sleep(interval) => {}
+                }
+                rate_limiter.acquire().await;
+                let orders = snapshot(&client, &url, None)
+                    .await
+                    .and_then(|snapshot| levels.update(snapshot));
+                if sender.send(orders).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let updates = futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+        let orders = futures::stream::once(async { Ok(first) })
+            .chain(updates)
+            .map_ok(|orders| futures::stream::iter(orders.into_iter().map(Ok)))
+            .try_flatten();
+        Ok(Box::pin(orders))
+    }
+}
+
+/// Quantities of the levels in the last snapshot, keyed by the bits of the price.
+#[derive(Default)]
+struct PolledLevels {
+    last_update_id: Option<u64>,
+    bids: HashMap<u64, f64>,
+    asks: HashMap<u64, f64>,
+}
+
+impl PolledLevels {
+    /// Replaces the levels with those of `snapshot`, returning the orders to apply the change.
+    fn update(&mut self, snapshot: Snapshot) -> Result<Vec<Order>, Error> {
+        if self.last_update_id == Some(snapshot.last_update_id) {
+            return Ok(Vec::new());
+        }
+        let bids = to_levels(snapshot.bids, InvalidPricePolicy::Reject)?;
+        let asks = to_levels(snapshot.asks, InvalidPricePolicy::Reject)?;
+        self.last_update_id = Some(snapshot.last_update_id);
+
+        let mut orders = Vec::new();
+        orders.extend(diff(&mut self.bids, bids).map(Order::Bid));
+        orders.extend(diff(&mut self.asks, asks).map(Order::Ask));
+        Ok(orders)
+    }
+}
+
+/// Replaces the levels of a side, returning the changed levels followed by removals.
+fn diff(
+    previous: &mut HashMap<u64, f64>,
+    levels: Vec<OrderDetails>,
+) -> impl Iterator<Item = OrderDetails> {
+    let mut current = HashMap::with_capacity(levels.len());
+    let mut changed = Vec::new();
+    for level in levels {
+        let key = level.price.to_bits();
+        current.insert(key, level.quantity);
+        if previous.remove(&key) != Some(level.quantity) {
+            changed.push(level);
+        }
+    }
+    let removed = std::mem::replace(previous, current).into_keys().map(|key| OrderDetails {
+        price: f64::from_bits(key),
+        quantity: 0.0,
+        event_time: None,
+    });
+    changed.into_iter().chain(removed)
+}
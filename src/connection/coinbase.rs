@@ -0,0 +1,105 @@
+use std::convert::TryFrom;
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::Connection;
+use super::ConnectionStream;
+use super::Error;
+use super::Order;
+use super::OrderDetails;
+
+/// Websocket feed of Coinbase Exchange.
+const FEED_URL: &str = "wss://ws-feed.exchange.coinbase.com";
+
+/// A connection to Coinbase Exchange for the specified product, e.g. "BTC-USD"
+pub struct CoinbaseConnection {
+    product_id: String,
+}
+
+impl CoinbaseConnection {
+    pub fn new(product_id: &str) -> Self {
+        Self {
+            product_id: product_id.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Connection for CoinbaseConnection {
+    async fn stream(&self) -> Result<ConnectionStream, Error> {
+        let (mut stream, _) = tokio_tungstenite::connect_async(FEED_URL).await?;
+        let subscribe = serde_json::json!({
+            "type": "subscribe",
+            "product_ids": [self.product_id],
+            "channels": ["level2"],
+        });
+        stream.send(Message::Text(subscribe.to_string())).await?;
+
+        // The feed starts with a snapshot, followed by updates.
+        let orders = stream
+            .filter_map(|result| async move {
+                match result {
+                    Ok(Message::Text(s)) => Some(
+                        serde_json::from_str::<Feed>(&s)
+                            .map_err(Error::from)
+                            .and_then(Vec::<Order>::try_from),
+                    ),
+                    // Answered by tungstenite.
+                    Ok(Message::Ping(_) | Message::Pong(_)) => None,
+                    Ok(other) => Some(Err(Error::UnexpectedItem(format!("{:?}", other)))),
+                    Err(e) => Some(Err(Error::from(e))),
+                }
+            })
+            .map_ok(|orders| futures::stream::iter(orders.into_iter().map(Ok)))
+            .try_flatten();
+        Ok(Box::pin(orders))
+    }
+}
+
+/// A message of the level2 channel.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Feed {
+    /// Full book as (price, size) levels.
+    Snapshot {
+        bids: Vec<(String, String)>,
+        asks: Vec<(String, String)>,
+    },
+    /// Changes as (side, price, size), where a size of zero removes the level.
+    L2update { changes: Vec<(String, String, String)> },
+    /// Error, e.g. for an unknown product.
+    Error { message: String },
+    /// Subscription confirmations and other messages, which carry no orders.
+    #[serde(other)]
+    Other,
+}
+
+impl TryFrom<Feed> for Vec<Order> {
+    type Error = Error;
+
+    fn try_from(value: Feed) -> Result<Self, Self::Error> {
+        match value {
+            Feed::Snapshot { bids, asks } => {
+                let bids = bids.into_iter().map(|level| Ok(Order::Bid(level.try_into()?)));
+                let asks = asks.into_iter().map(|level| Ok(Order::Ask(level.try_into()?)));
+                bids.chain(asks).collect()
+            }
+            Feed::L2update { changes } => changes
+                .into_iter()
+                .map(|(side, price, size)| {
+                    let details = OrderDetails::try_from((price, size))?;
+                    match side.as_str() {
+                        "buy" => Ok(Order::Bid(details)),
+                        "sell" => Ok(Order::Ask(details)),
+                        _ => Err(Error::UnexpectedItem(format!("Unknown side {}", side))),
+                    }
+                })
+                .collect(),
+            Feed::Error { message } => Err(Error::UnexpectedItem(message)),
+            Feed::Other => Ok(Vec::new()),
+        }
+    }
+}
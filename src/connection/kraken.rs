@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::Connection;
+use super::ConnectionStream;
+use super::Error;
+use super::ExchangeTime;
+use super::Order;
+use super::OrderDetails;
+
+/// Public websocket API of Kraken.
+const FEED_URL: &str = "wss://ws.kraken.com";
+
+/// Subscribed book depth, which is also the depth the checksum covers.
+const DEPTH: usize = 10;
+
+/// A connection to Kraken for the specified pair, e.g. "XBT/USD"
+pub struct KrakenConnection {
+    pair: String,
+}
+
+impl KrakenConnection {
+    pub fn new(pair: &str) -> Self {
+        Self {
+            pair: pair.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Connection for KrakenConnection {
+    async fn stream(&self) -> Result<ConnectionStream, Error> {
+        let (mut stream, _) = tokio_tungstenite::connect_async(FEED_URL).await?;
+        let subscribe = serde_json::json!({
+            "event": "subscribe",
+            "pair": [self.pair],
+            "subscription": { "name": "book", "depth": DEPTH },
+        });
+        stream.send(Message::Text(subscribe.to_string())).await?;
+
+        let mut book = KrakenBook::default();
+        let orders = stream
+            .filter_map(move |result| {
+                let orders = match result {
+                    Ok(Message::Text(s)) => Some(book.apply(&s)),
+                    // Answered by tungstenite.
+                    Ok(Message::Ping(_) | Message::Pong(_)) => None,
+                    Ok(other) => Some(Err(Error::UnexpectedItem(format!("{:?}", other)))),
+                    Err(e) => Some(Err(Error::from(e))),
+                };
+                futures::future::ready(orders)
+            })
+            .map_ok(|orders| futures::stream::iter(orders.into_iter().map(Ok)))
+            .try_flatten();
+        Ok(Box::pin(orders))
+    }
+}
+
+/// Local copy of the subscribed depth, keeping the levels as received for the checksum.
+#[derive(Default)]
+struct KrakenBook {
+    // Keyed by the bits of the (positive) price, which order as the price does.
+    bids: BTreeMap<u64, (String, String)>,
+    asks: BTreeMap<u64, (String, String)>,
+}
+
+impl KrakenBook {
+    /// Applies a message to the book and returns its orders. Fails if the book's checksum
+    /// does not match the one in the message.
+    fn apply(&mut self, text: &str) -> Result<Vec<Order>, Error> {
+        let parts = match serde_json::from_str::<Value>(text)? {
+            Value::Array(parts) => parts,
+            // Heartbeats and status events, which carry no orders.
+            event => {
+                if event["status"] == "error" {
+                    let message = event["errorMessage"].as_str().unwrap_or("Unknown error");
+                    return Err(Error::UnexpectedItem(message.to_owned()));
+                }
+                return Ok(Vec::new());
+            }
+        };
+
+        // [channel id, data..., channel name, pair], with up to two data objects.
+        let mut orders = Vec::new();
+        let mut checksum = None;
+        let data = parts.iter().skip(1).take(parts.len().saturating_sub(3));
+        for (key, value) in data.filter_map(Value::as_object).flatten() {
+            match key.as_str() {
+                "as" | "a" => self.apply_levels(value, false, &mut orders)?,
+                "bs" | "b" => self.apply_levels(value, true, &mut orders)?,
+                "c" => checksum = value.as_str(),
+                _ => {}
+            }
+        }
+        self.truncate(&mut orders);
+
+        if let Some(expected) = checksum {
+            let actual = self.checksum().to_string();
+            if actual != expected {
+                return Err(Error::UnexpectedItem(format!(
+                    "Book checksum mismatch: expected {}, calculated {}",
+                    expected, actual
+                )));
+            }
+        }
+        Ok(orders)
+    }
+
+    /// Applies levels given as [price, volume, timestamp, ...] arrays to the bid or ask side.
+    fn apply_levels(
+        &mut self,
+        levels: &Value,
+        bid: bool,
+        orders: &mut Vec<Order>,
+    ) -> Result<(), Error> {
+        let levels = levels.as_array().map(Vec::as_slice).unwrap_or_default();
+        for level in levels {
+            let field = |i: usize| level[i].as_str().unwrap_or_default().to_owned();
+            let (price, volume, timestamp) = (field(0), field(1), field(2));
+            let mut details = OrderDetails::<f64>::try_from((price.clone(), volume.clone()))?;
+            details.event_time = timestamp
+                .parse::<f64>()
+                .ok()
+                .map(|secs| ExchangeTime((secs * 1000.0) as u64));
+
+            let levels = if bid { &mut self.bids } else { &mut self.asks };
+            if details.quantity == 0.0 {
+                levels.remove(&details.price.to_bits());
+            } else {
+                levels.insert(details.price.to_bits(), (price, volume));
+            }
+            orders.push(if bid { Order::Bid(details) } else { Order::Ask(details) });
+        }
+        Ok(())
+    }
+
+    /// Removes levels beyond the subscribed depth, which the exchange stops updating without
+    /// deleting them.
+    fn truncate(&mut self, orders: &mut Vec<Order>) {
+        let removal = |key: u64| OrderDetails {
+            price: f64::from_bits(key),
+            quantity: 0.0,
+            event_time: None,
+        };
+        while self.bids.len() > DEPTH {
+            let (key, _) = self.bids.pop_first().unwrap();
+            orders.push(Order::Bid(removal(key)));
+        }
+        while self.asks.len() > DEPTH {
+            let (key, _) = self.asks.pop_last().unwrap();
+            orders.push(Order::Ask(removal(key)));
+        }
+    }
+
+    /// Calculates Kraken's CRC32 checksum of the top asks, best first, followed by the top bids,
+    /// best first. Each level contributes its price and volume with the decimal point and
+    /// leading zeros removed.
+    fn checksum(&self) -> u32 {
+        let mut input = String::new();
+        let asks = self.asks.values();
+        let bids = self.bids.values().rev();
+        for (price, volume) in asks.take(DEPTH).chain(bids.take(DEPTH)) {
+            for value in [price, volume] {
+                input.extend(value.chars().filter(|&c| c != '.').skip_while(|&c| c == '0'));
+            }
+        }
+        crc32(input.as_bytes())
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Levels of Kraken's checksum example, as [price, volume, timestamp] arrays.
+    fn levels(prices: [&str; 10]) -> Value {
+        let level = |price| serde_json::json!([price, "0.00000500", "1582905487.684110"]);
+        Value::Array(prices.into_iter().map(level).collect())
+    }
+
+    /// The book of Kraken's checksum example, whose checksum is 974947235.
+    fn example_book() -> KrakenBook {
+        let asks = levels([
+            "0.05005", "0.05010", "0.05015", "0.05020", "0.05025", "0.05030", "0.05035", "0.05040",
+            "0.05045", "0.05050",
+        ]);
+        let bids = levels([
+            "0.05000", "0.04995", "0.04990", "0.04980", "0.04975", "0.04970", "0.04965", "0.04960",
+            "0.04955", "0.04950",
+        ]);
+        let snapshot = serde_json::json!([0, { "as": asks, "bs": bids }, "book-10", "XBT/USD"]);
+        let mut book = KrakenBook::default();
+        assert_eq!(book.apply(&snapshot.to_string()).unwrap().len(), 20);
+        book
+    }
+
+    #[test]
+    fn checksum_matches_the_known_good_value() {
+        assert_eq!(example_book().checksum(), 974947235);
+    }
+
+    #[test]
+    fn update_checksums_are_validated() {
+        let update = |checksum: &str| {
+            let level = ["0.05005", "0.00000500", "1582905487.684110"];
+            let data = serde_json::json!({ "a": [level], "c": checksum });
+            serde_json::json!([0, data, "book-10", "XBT/USD"]).to_string()
+        };
+        let mut book = example_book();
+        assert_eq!(book.apply(&update("974947235")).unwrap().len(), 1);
+        let mismatch = book.apply(&update("123"));
+        assert!(matches!(mismatch, Err(Error::UnexpectedItem(_))));
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use super::Connection;
+use super::ConnectionStream;
+use super::Error;
+use super::Number;
+use super::Order;
+
+/// A connection that streams a fixed sequence of orders and errors, then stays open without
+/// further items, for driving an order book deterministically in tests. Reconnections stream
+/// nothing. The orders are deltas, applied on top of the book's levels.
+///
+/// ```
+/// use byte_test::connection::{MockConnection, Order, OrderDetails};
+/// use byte_test::order_book::OrderBook;
+///
+/// # #[tokio::main(flavor = "current_thread", start_paused = true)]
+/// # async fn main() {
+/// let bid = |price| Ok(Order::Bid(OrderDetails { price, quantity: 1.0, event_time: None }));
+/// let book = OrderBook::create(MockConnection::new(vec![bid(100.0), bid(101.0)])).await;
+///
+/// // Let the book apply the orders. The clock is paused, so this ends once the book is idle.
+/// tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+/// assert_eq!(book.top_bid_ask().await, (Some(101.0), None));
+/// # }
+/// ```
+pub struct MockConnection<P = f64> {
+    items: Mutex<Vec<Result<Order<P>, Error>>>,
+}
+
+impl<P> MockConnection<P> {
+    pub fn new(items: Vec<Result<Order<P>, Error>>) -> Self {
+        Self {
+            items: Mutex::new(items),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Number> Connection<P> for MockConnection<P> {
+    async fn stream(&self) -> Result<ConnectionStream<P>, Error> {
+        let items = std::mem::take(&mut *self.items.lock().unwrap());
+        Ok(Box::pin(futures::stream::iter(items).chain(futures::stream::pending())))
+    }
+
+    fn starts_with_snapshot(&self) -> bool {
+        false
+    }
+}
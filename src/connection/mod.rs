@@ -1,77 +1,310 @@
-use std::cmp::Ordering;
-use std::num::ParseFloatError;
+use std::fmt;
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use reqwest::Error as ReqwestError;
+use serde::Deserialize;
+use serde::Serialize;
 use serde_json::Error as JsonError;
 use thiserror::Error;
 use tokio_tungstenite::tungstenite::Error as TungsteniteError;
 use futures::Stream;
 
 mod binance;
+mod binance_combined;
+mod binance_futures;
+mod binance_polling;
+mod coinbase;
+mod kraken;
+#[cfg(any(test, feature = "testing"))]
+mod mock;
+mod rate_limit;
 
+pub use binance::parse_depth_update;
 pub use binance::BinanceConnection;
+pub use binance_combined::BinanceCombinedConnection;
+pub use binance_futures::BinanceFuturesConnection;
+pub use binance::BinanceRegion;
+pub use binance::UpdateSpeed;
+pub use binance_polling::BinancePollingConnection;
+pub use coinbase::CoinbaseConnection;
+pub use kraken::KrakenConnection;
+#[cfg(any(test, feature = "testing"))]
+pub use mock::MockConnection;
+pub use rate_limit::NoRateLimit;
+pub use rate_limit::RateLimiter;
+pub use rate_limit::TokenBucket;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
     Rest(#[from] ReqwestError),
     #[error(transparent)]
-    Stream(#[from] TungsteniteError),
+    Stream(Box<TungsteniteError>),
     #[error(transparent)]
     ParseJson(#[from] JsonError),
     #[error("Unexpected stream item {0}")]
     UnexpectedItem(String),
-    #[error(transparent)]
-    ParseFloat(#[from] ParseFloatError),
+    #[error("Invalid number {0}")]
+    ParseNumber(String),
+    #[error("Invalid price {0}")]
+    InvalidPrice(String),
+    #[error("Invalid symbol {0}")]
+    InvalidSymbol(String),
+    #[error("Rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    #[error("Stream closed by the exchange with code {code}: {reason}")]
+    StreamClosed { code: u16, reason: String },
+}
+
+impl From<TungsteniteError> for Error {
+    fn from(e: TungsteniteError) -> Self {
+        Error::Stream(Box::new(e))
+    }
+}
+
+impl Error {
+    /// Returns the HTTP status code of a failed REST request, if any.
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            Error::Rest(e) => e.status().map(|status| status.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// Returns true if a REST request was rejected for exceeding the rate limit (429), or because
+    /// the client has been banned for ignoring it (418).
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, Error::RateLimited { .. })
+            || matches!(self.http_status(), Some(429) | Some(418))
+    }
+
+    /// Returns how long the exchange asked to wait before the next request, if rate limited.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the error leaves the book out of sync, so it must be cleared and resynced
+    /// from a fresh connection. Otherwise only the offending message was skipped, and the rest
+    /// of the stream can still be applied.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Rest(_) | Error::Stream(_) | Error::UnexpectedItem(_) => true,
+            Error::InvalidSymbol(_) | Error::RateLimited { .. } => true,
+            Error::StreamClosed { .. } => true,
+            Error::ParseJson(_) | Error::ParseNumber(_) | Error::InvalidPrice(_) => false,
+        }
+    }
+}
+
+/// Numeric type of prices and quantities, e.g. `f64`, `f32` or a decimal type. Zero is the
+/// `Default` value.
+pub trait Number:
+    FromStr<Err: fmt::Display>
+    + PartialOrd
+    + Copy
+    + Default
+    + fmt::Debug
+    + fmt::Display
+    + Send
+    + Sync
+    + Unpin
+    + 'static
+{
+}
+
+impl<T> Number for T where
+    T: FromStr<Err: fmt::Display>
+        + PartialOrd
+        + Copy
+        + Default
+        + fmt::Debug
+        + fmt::Display
+        + Send
+        + Sync
+        + Unpin
+        + 'static
+{
 }
 
 /// Order
-pub enum Order {
+pub enum Order<P = f64> {
     /// Bid order
-    Bid(OrderDetails),
+    Bid(OrderDetails<P>),
     /// Ask order
-    Ask(OrderDetails),
+    Ask(OrderDetails<P>),
+}
+
+/// An order tagged with the symbol of its book, from a connection streaming several symbols.
+pub struct SymbolOrder<P = f64> {
+    pub symbol: Arc<str>,
+    pub order: Order<P>,
+}
+
+/// A trade print.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trade<P = f64> {
+    pub price: P,
+    pub quantity: P,
+    /// Exchange time of the trade.
+    pub timestamp: ExchangeTime,
+    /// Whether the buyer placed the resting order, i.e. the seller was the aggressor.
+    pub is_buyer_maker: bool,
+}
+
+/// Rule for whether the first delta after a snapshot continues it. Deltas cover the update id
+/// range `first_update..=last_update`, and the snapshot includes updates up to `snapshot_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sequencing {
+    /// The first delta must start exactly at the update after the snapshot.
+    Contiguous,
+    /// The first delta may start at or before the update after the snapshot, as long as its
+    /// range includes it. Updates already in the snapshot are reapplied.
+    Overlapping,
+    /// As for USD-M futures: the first delta's range must include the snapshot's last update,
+    /// and each following delta must give the previous one's last update id.
+    Futures,
+}
+
+impl Sequencing {
+    /// Returns true if a delta with the given update id range may be applied first on top of a
+    /// snapshot with the given last update id.
+    pub fn is_valid_first(&self, snapshot_id: u64, first_update: u64, last_update: u64) -> bool {
+        let next = snapshot_id + 1;
+        match self {
+            Sequencing::Contiguous => first_update == next,
+            Sequencing::Overlapping => first_update <= next && last_update >= next,
+            Sequencing::Futures => first_update <= snapshot_id && last_update >= snapshot_id,
+        }
+    }
+}
+
+/// Handling of levels with a non-positive price, which would corrupt the book's ordering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidPricePolicy {
+    /// Reject the frame with `Error::InvalidPrice`, so the book resyncs.
+    #[default]
+    Reject,
+    /// Drop the level and apply the rest of the frame.
+    Drop,
 }
 
 /// Type of a connection stream
-pub type ConnectionStream = Pin<Box<dyn Stream<Item = Result<Order, Error>> + Send + Sync>>;
+pub type ConnectionStream<P = f64> =
+    Pin<Box<dyn Stream<Item = Result<Order<P>, Error>> + Send + Sync>>;
+
+/// Type of a stream of orders for several symbols
+pub type SymbolOrderStream<P = f64> =
+    Pin<Box<dyn Stream<Item = Result<SymbolOrder<P>, Error>> + Send + Sync>>;
 
-/// Trait implemented by different connections
+/// Type of a trade stream
+pub type TradeStream<P = f64> = Pin<Box<dyn Stream<Item = Result<Trade<P>, Error>> + Send + Sync>>;
+
+/// Trait implemented by different connections, streaming prices and quantities as `P`
 #[async_trait]
-pub trait Connection: Send + Sync + 'static {
-    async fn stream(&self) -> Result<ConnectionStream, Error>;
+pub trait Connection<P = f64>: Send + Sync + 'static {
+    async fn stream(&self) -> Result<ConnectionStream<P>, Error>;
+
+    /// Whether each stream starts with the exchange's snapshot, which replaces the book's
+    /// levels. Otherwise streams only carry deltas, applied on top of the book's levels.
+    fn starts_with_snapshot(&self) -> bool {
+        true
+    }
 }
 
-/// An order.
-pub struct OrderDetails {
-    pub price: f32,
-    pub quantity: f32,
+/// A timestamp from an exchange, in milliseconds since the Unix epoch (UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ExchangeTime(pub u64);
+
+impl ExchangeTime {
+    /// Returns the milliseconds since the Unix epoch.
+    pub fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    /// Converts to a `SystemTime`.
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.0)
+    }
+
+    /// Converts to a UTC `DateTime`, or `None` if out of its range.
+    #[cfg(feature = "chrono")]
+    pub fn to_datetime(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::from_timestamp_millis(i64::try_from(self.0).ok()?)
+    }
 }
 
-impl PartialEq for OrderDetails {
-    fn eq(&self, other: &Self) -> bool {
-        self.price == other.price
+impl From<u64> for ExchangeTime {
+    fn from(millis: u64) -> Self {
+        Self(millis)
     }
 }
 
-impl Eq for OrderDetails {}
+impl From<ExchangeTime> for SystemTime {
+    fn from(time: ExchangeTime) -> Self {
+        time.to_system_time()
+    }
+}
+
+/// Displays as seconds since the Unix epoch, with millisecond precision.
+impl fmt::Display for ExchangeTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03}", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+/// An order.
+pub struct OrderDetails<P = f64> {
+    pub price: P,
+    pub quantity: P,
+    /// Exchange time of the update carrying this order, if known.
+    pub event_time: Option<ExchangeTime>,
+}
 
-impl Ord for OrderDetails {
-    fn cmp(&self, other: &Self) -> Ordering {
-        if self.price < other.price {
-            Ordering::Less
-        } else if self.price > other.price {
-            Ordering::Greater
-        } else {
-            Ordering::Equal
+impl OrderDetails {
+    /// Creates order details from integer-encoded values, where `price_scale` and `qty_scale`
+    /// are the number of implied decimal places (e.g. 8 for a price in satoshis).
+    pub fn from_scaled(price_int: i64, qty_int: i64, price_scale: u32, qty_scale: u32) -> Self {
+        Self {
+            price: price_int as f64 / 10f64.powi(price_scale as i32),
+            quantity: qty_int as f64 / 10f64.powi(qty_scale as i32),
+            event_time: None,
         }
     }
 }
 
-impl PartialOrd for OrderDetails {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exchange_time_conversions() {
+        assert_eq!(ExchangeTime(0).to_system_time(), UNIX_EPOCH);
+        assert_eq!(SystemTime::from(ExchangeTime::from(1)), UNIX_EPOCH + Duration::from_millis(1));
+        assert_eq!(ExchangeTime(1_700_000_000_123).as_millis(), 1_700_000_000_123);
+
+        assert_eq!(ExchangeTime(0).to_string(), "0.000");
+        assert_eq!(ExchangeTime(999).to_string(), "0.999");
+        assert_eq!(ExchangeTime(1_000).to_string(), "1.000");
+        assert_eq!(ExchangeTime(1_700_000_000_005).to_string(), "1700000000.005");
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn exchange_time_to_datetime() {
+        let epoch = ExchangeTime(0).to_datetime().unwrap();
+        assert_eq!(epoch.to_rfc3339(), "1970-01-01T00:00:00+00:00");
+        let time = ExchangeTime(1_700_000_000_123).to_datetime().unwrap();
+        assert_eq!(time.to_rfc3339(), "2023-11-14T22:13:20.123+00:00");
+
+        // Out of range of DateTime, or of i64.
+        assert_eq!(ExchangeTime(i64::MAX as u64).to_datetime(), None);
+        assert_eq!(ExchangeTime(u64::MAX).to_datetime(), None);
+    }
+}
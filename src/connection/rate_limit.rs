@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Policy for pacing requests to an exchange. Share one instance between connections to limit
+/// their combined request rate.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    /// Waits until a request may be made.
+    async fn acquire(&self);
+}
+
+/// Rate limiter that never waits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoRateLimit;
+
+#[async_trait]
+impl RateLimiter for NoRateLimit {
+    async fn acquire(&self) {}
+}
+
+/// Token bucket rate limiter: allows bursts of up to `capacity` requests, refilled at one
+/// token per `refill_interval`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_interval: Duration,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket. The capacity is at least 1.
+    pub fn new(capacity: u32, refill_interval: Duration) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity: capacity as f64,
+            refill_interval,
+            state: Mutex::new(BucketState {
+                tokens: capacity as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimiter for TokenBucket {
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = Instant::now();
+                let elapsed = now - state.refilled_at;
+                let refilled = if self.refill_interval.is_zero() {
+                    self.capacity
+                } else {
+                    elapsed.as_secs_f64() / self.refill_interval.as_secs_f64()
+                };
+                state.tokens = (state.tokens + refilled).min(self.capacity);
+                state.refilled_at = now;
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    return;
+                }
+                self.refill_interval.mul_f64(1.0 - state.tokens)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
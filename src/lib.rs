@@ -0,0 +1,3 @@
+pub mod connection;
+pub mod order_book;
+pub mod order_book_manager;
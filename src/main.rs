@@ -1,17 +1,19 @@
-use connection::BinanceConnection;
-use order_book::OrderBook;
-
-mod connection;
-mod order_book;
+use byte_test::connection::BinanceConnection;
+use byte_test::order_book::OrderBook;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() {
+    // Log levels are set by RUST_LOG, e.g. RUST_LOG=byte_test=debug, defaulting to info.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
     let binance_connection = BinanceConnection::new("BTCUSDT");
     let order_book = OrderBook::create(binance_connection).await;
 
     loop {
         let (bid, ask) = order_book.top_bid_ask().await;
-        println!("Bid: {}, Ask: {}", bid.unwrap_or(f32::NAN), ask.unwrap_or(f32::NAN));
+        tracing::info!(?bid, ?ask, "Top of book");
         tokio::time::sleep(std::time::Duration::from_secs(3)).await;
     }
 }
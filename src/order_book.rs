@@ -1,88 +1,2146 @@
-use std::collections::BTreeSet;
+use std::collections::hash_map::RandomState;
+use std::cmp::Ordering;
 use std::cmp::Reverse;
+use std::fmt;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering as AtomicOrdering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+use arc_swap::ArcSwap;
+use futures::FutureExt;
 use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::watch;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 use crate::connection::Connection;
+use crate::connection::ConnectionStream;
+use crate::connection::Error;
+use crate::connection::ExchangeTime;
+use crate::connection::Number;
 use crate::connection::Order;
 use crate::connection::OrderDetails;
 
-/// The order book
-pub struct OrderBook {
-    book: Arc<RwLock<Book>>,
+/// The order book, with prices and quantities of type `P`
+///
+/// # Metrics
+///
+/// With the `metrics` feature, the latency from each update's exchange event time to it being
+/// applied is recorded in seconds to the `order_book_update_latency_seconds` histogram.
+/// Latencies range from well under a millisecond to seconds during exchange or network stalls,
+/// so exponential buckets such as 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+/// 0.5, 1, 2.5 and 5 are recommended, e.g. with the Prometheus exporter:
+///
+/// ```ignore
+/// PrometheusBuilder::new().set_buckets_for_metric(
+///     Matcher::Full("order_book_update_latency_seconds".to_owned()),
+///     &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0],
+/// )?;
+/// ```
+///
+/// Latencies are relative to the local clock, so clock skew shifts them.
+pub struct OrderBook<P = f64> {
+    book: Arc<RwLock<Book<P>>>,
+    snapshot: Arc<ArcSwap<ImmutableBook<P>>>,
+    reconnect_callbacks: Arc<Mutex<Vec<ReconnectCallback>>>,
+    error_callbacks: Arc<Mutex<Vec<ErrorCallback>>>,
+    metrics: Arc<BookMetrics>,
+    updates: broadcast::Sender<BookUpdate<P>>,
+    state: watch::Receiver<BookState>,
+    min_display_quantity: P,
+    options: OrderBookOptions<P>,
+    /// Cancelled to stop the background tasks.
+    shutdown: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
 }
 
-struct Book {
-    // TODO BTreeSet is not an optimal data structure for this, a binary heap would be better, but
-    // TODO std lib binary heap does not support removal of elements, and could not immediately
-    // TODO 3rd party impl of e.g. Fibonacci heap or similar.
-    bids: BTreeSet<Reverse<OrderDetails>>,
-    asks: BTreeSet<OrderDetails>,
+/// Callback invoked on reconnection.
+type ReconnectCallback = Box<dyn Fn(&ReconnectInfo) + Send + Sync>;
+
+/// Callback invoked on connection errors.
+type ErrorCallback = Box<dyn Fn(&Error) + Send + Sync>;
+
+/// Callback invoked on connection errors, set through `OrderBookOptions::on_error`.
+pub type OnError = Arc<dyn Fn(&Error) + Send + Sync>;
+
+/// Maximum number of already available stream items applied as one batch.
+const MAX_READY_BATCH: usize = 10_000;
+
+/// Interval between reconnection attempts during a maintenance window.
+const MAINTENANCE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of top of book updates kept for slow subscribers, which skip any older updates.
+const UPDATES_CAPACITY: usize = 1024;
+
+/// Delay before the first reconnection attempt after a connection is lost.
+const RECONNECT_BACKOFF_INITIAL: Duration = Duration::from_millis(250);
+
+/// Maximum delay between reconnection attempts.
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Side of the order book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// Bid side
+    Bid,
+    /// Ask side
+    Ask,
 }
 
-impl OrderBook {
+/// An immutable copy of the book, published for lock-free reads.
+#[derive(Debug, Clone, Default)]
+pub struct ImmutableBook<P = f64> {
+    /// Bid levels as (price, quantity), best price first.
+    pub bids: Vec<(P, P)>,
+    /// Ask levels as (price, quantity), best price first.
+    pub asks: Vec<(P, P)>,
+    /// Exchange time of the latest applied update, if known.
+    pub last_event_time: Option<ExchangeTime>,
+}
+
+impl<P: Copy> ImmutableBook<P> {
+    /// Returns the (possibly empty) top bid and ask.
+    pub fn top_bid_ask(&self) -> (Option<P>, Option<P>) {
+        (
+            self.bids.first().map(|(price, _)| *price),
+            self.asks.first().map(|(price, _)| *price),
+        )
+    }
+}
+
+/// A change of the top of book, sent to `OrderBook::subscribe` receivers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BookUpdate<P = f64> {
+    /// The (possibly empty) new top bid.
+    pub bid: Option<P>,
+    /// The (possibly empty) new top ask.
+    pub ask: Option<P>,
+}
+
+/// State of an order book's connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BookState {
+    /// Connecting for the first time.
+    Connecting,
+    /// Connected and applying updates.
+    Live,
+    /// Connection lost, reconnecting.
+    Reconnecting,
+    /// Connected, but no update received for `OrderBookOptions::stale_after`, or holding levels
+    /// known to be out of date: during a maintenance window, or until a resync replaces them.
+    Stale,
+    /// Gave up after `OrderBookOptions::max_reconnect_attempts` failed attempts. The book is
+    /// cleared and no longer updated.
+    Failed,
+}
+
+/// Details of a reconnection, passed to `OrderBook::on_reconnect` callbacks.
+#[derive(Debug)]
+pub struct ReconnectInfo<'a> {
+    /// Number of connection attempts made since the previous connection was lost.
+    pub attempt: u32,
+    /// The last error since the previous connection was lost, if any.
+    pub last_error: Option<&'a Error>,
+    /// Time since the previous connection was lost.
+    pub downtime: Duration,
+}
+
+/// A period during which the exchange is expected to be unavailable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MaintenanceWindow {
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl MaintenanceWindow {
+    /// Returns true if `time` falls within the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+/// Optional behaviour of an order book.
+#[derive(Clone, Default, Serialize)]
+pub struct OrderBookOptions<P = f64> {
+    /// Interval at which to log a heartbeat with the top of book and last update age, regardless
+    /// of update activity. Disabled when `None`.
+    pub heartbeat_interval: Option<Duration>,
+    /// Number of removals of price levels absent from the book, since the last (re)connect, after
+    /// which the book is assumed to have missed updates and is resynced, keeping its levels as
+    /// `BookState::Stale` until the new snapshot replaces them. Removals beyond the deepest
+    /// level of a side are not counted, as the book may never have held such levels, e.g.
+    /// beyond the snapshot's depth. Disabled when `None`.
+    pub absent_removal_threshold: Option<u64>,
+    /// Window over which to collect updates before applying them under a single write lock,
+    /// bounding how often readers are blocked on hot symbols. Updates are applied as they
+    /// arrive when `None`.
+    pub coalesce_window: Option<Duration>,
+    /// Minimum quantity of levels returned by display accessors such as `depth_columnar`. The
+    /// book itself keeps every level.
+    pub min_display_quantity: Option<P>,
+    /// Scheduled exchange maintenance. While inside a window, the book keeps its levels with
+    /// its state `BookState::Stale`, and reconnection is only retried once a minute rather than
+    /// with the usual backoff.
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Publish an immutable copy of the book after every update, for lock-free reads through
+    /// `OrderBook::snapshot_fast`. Costs a copy of the book per update.
+    pub lock_free_snapshot: bool,
+    /// Time without updates from the connection after which the book's state becomes
+    /// `BookState::Stale`, until the next update. Disabled when `None`.
+    pub stale_after: Option<Duration>,
+    /// Maximum number of levels kept per side, at least 1. Levels furthest from the top are
+    /// pruned beyond it, and later removals of pruned levels are not counted as absent. All
+    /// levels are kept when `None`.
+    pub max_depth: Option<usize>,
+    /// Interval at which to reconnect and rebuild the book from a fresh snapshot, discarding
+    /// any drift. The book keeps its levels until the new snapshot replaces them. Disabled
+    /// when `None`.
+    pub resync_interval: Option<Duration>,
+    /// Number of consecutive failed connection attempts after which the book gives up, and its
+    /// state becomes `BookState::Failed`. Retries indefinitely when `None`.
+    pub max_reconnect_attempts: Option<u32>,
+    /// Callback invoked for each error connecting or from the connection's stream, as for
+    /// `OrderBook::on_error`, but also for errors of the first connection attempt.
+    #[serde(skip)]
+    pub on_error: Option<OnError>,
+}
+
+impl<P: fmt::Debug> fmt::Debug for OrderBookOptions<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderBookOptions")
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("absent_removal_threshold", &self.absent_removal_threshold)
+            .field("coalesce_window", &self.coalesce_window)
+            .field("min_display_quantity", &self.min_display_quantity)
+            .field("maintenance_windows", &self.maintenance_windows)
+            .field("lock_free_snapshot", &self.lock_free_snapshot)
+            .field("stale_after", &self.stale_after)
+            .field("max_depth", &self.max_depth)
+            .field("resync_interval", &self.resync_interval)
+            .field("max_reconnect_attempts", &self.max_reconnect_attempts)
+            .field("on_error", &self.on_error.as_ref().map(|_| "Fn(&Error)"))
+            .finish()
+    }
+}
+
+/// Counts of events of an order book since it was created, as of one point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct BookMetricsSnapshot {
+    /// Orders from the connection applied to the book.
+    pub deltas_applied: u64,
+    /// Errors connecting or from the connection's stream.
+    pub errors: u64,
+    /// Reconnections after losing the connection.
+    pub reconnects: u64,
+    /// Levels pruned beyond `OrderBookOptions::max_depth`.
+    pub levels_pruned: u64,
+    /// Removals of price levels that were not in the book, within the depth of its side.
+    pub absent_removals: u64,
+    /// Microseconds from the exchange time of the latest update to when it was applied, if any
+    /// update had an exchange time. Negative if the local clock is behind the exchange's, so
+    /// only meaningful when both are synced, e.g. by NTP.
+    pub apply_latency_us: Option<i64>,
+}
+
+/// Counters of an order book's events, updated by its update task.
+#[derive(Debug)]
+struct BookMetrics {
+    deltas_applied: AtomicU64,
+    errors: AtomicU64,
+    reconnects: AtomicU64,
+    levels_pruned: AtomicU64,
+    absent_removals: AtomicU64,
+    /// Latest apply latency in microseconds, or `i64::MIN` if none was measured.
+    apply_latency_us: AtomicI64,
+}
+
+impl Default for BookMetrics {
+    fn default() -> Self {
+        Self {
+            deltas_applied: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            levels_pruned: AtomicU64::new(0),
+            absent_removals: AtomicU64::new(0),
+            apply_latency_us: AtomicI64::new(i64::MIN),
+        }
+    }
+}
+
+impl BookMetrics {
+    fn snapshot(&self) -> BookMetricsSnapshot {
+        let apply_latency_us = self.apply_latency_us.load(AtomicOrdering::Relaxed);
+        BookMetricsSnapshot {
+            deltas_applied: self.deltas_applied.load(AtomicOrdering::Relaxed),
+            errors: self.errors.load(AtomicOrdering::Relaxed),
+            reconnects: self.reconnects.load(AtomicOrdering::Relaxed),
+            levels_pruned: self.levels_pruned.load(AtomicOrdering::Relaxed),
+            absent_removals: self.absent_removals.load(AtomicOrdering::Relaxed),
+            apply_latency_us: (apply_latency_us != i64::MIN).then_some(apply_latency_us),
+        }
+    }
+}
+
+/// Returns the microseconds from `event_time` to `now`, negative if `now` is earlier.
+fn latency_us(event_time: ExchangeTime, now: SystemTime) -> i64 {
+    let micros = |duration: Duration| i64::try_from(duration.as_micros()).unwrap_or(i64::MAX);
+    match now.duration_since(event_time.to_system_time()) {
+        Ok(latency) => micros(latency),
+        Err(e) => -micros(e.duration()),
+    }
+}
+
+/// Internal state of an order book, to attach to bug reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct DebugDump<P = f64> {
+    /// Bid levels as (price, quantity), best price first.
+    pub bids: Vec<(P, P)>,
+    /// Ask levels as (price, quantity), best price first.
+    pub asks: Vec<(P, P)>,
+    /// Exchange time of the latest applied update, if known.
+    pub last_event_time: Option<ExchangeTime>,
+    /// Time since the last order was applied, if any.
+    pub last_update_age: Option<Duration>,
+    /// State of the book's connection.
+    pub state: BookState,
+    /// Counts of the book's events.
+    pub metrics: BookMetricsSnapshot,
+    /// Options the book was created with.
+    pub options: OrderBookOptions<P>,
+}
+
+/// The levels of a book at one point in time, to save or feed to other systems.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookSnapshot<P = f64> {
+    /// Bid levels as (price, quantity), best price first.
+    pub bids: Vec<(P, P)>,
+    /// Ask levels as (price, quantity), best price first.
+    pub asks: Vec<(P, P)>,
+    /// Exchange time of the latest applied update, if known.
+    pub last_event_time: Option<ExchangeTime>,
+}
+
+struct Book<P> {
+    // Quantities by price, bids descending and asks ascending, so the best level of each side is
+    // its first.
+    bids: BTreeMap<Reverse<PriceKey<P>>, P>,
+    asks: BTreeMap<PriceKey<P>, P>,
+    /// Local time at which the last order was applied.
+    last_update: Option<Instant>,
+    /// Exchange time of the latest applied update, if known.
+    last_event_time: Option<ExchangeTime>,
+    /// Where to publish immutable copies of the book, if enabled.
+    published: Option<Arc<ArcSwap<ImmutableBook<P>>>>,
+    /// Where to send changes of the top of book.
+    updates: broadcast::Sender<BookUpdate<P>>,
+    /// Top bid and ask as of the last publish.
+    published_top: (Option<P>, Option<P>),
+    /// Maximum number of levels kept per side.
+    max_depth: usize,
+    /// Best (bid, ask) price pruned since the book was last cleared. Levels at or beyond it may
+    /// have been pruned.
+    pruned: (Option<P>, Option<P>),
+    /// Side of the last level set by the orders being applied, to uncross the book from once
+    /// they all are.
+    last_set: Option<Side>,
+    metrics: Arc<BookMetrics>,
+}
+
+/// A price ordered for use as a key. Prices that do not compare, i.e. NaN, are rejected before
+/// reaching the book (see `check_price`), so are treated as equal.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PriceKey<P>(pub(crate) P);
+
+impl<P: PartialOrd> PartialEq for PriceKey<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<P: PartialOrd> Eq for PriceKey<P> {}
+
+impl<P: PartialOrd> Ord for PriceKey<P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl<P: PartialOrd> PartialOrd for PriceKey<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P: Number> Book<P> {
+    fn top_bid_ask(&self) -> (Option<P>, Option<P>) {
+        (
+            self.bids.keys().next().map(|Reverse(price)| price.0),
+            self.asks.keys().next().map(|price| price.0),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+        self.pruned = (None, None);
+    }
+
+    /// Publishes an immutable copy of the book, if enabled, and sends the top of book to
+    /// subscribers if it has changed.
+    fn publish(&mut self) {
+        let top = self.top_bid_ask();
+        if top != self.published_top {
+            self.published_top = top;
+            // No subscribers is not an error.
+            let _ = self.updates.send(BookUpdate { bid: top.0, ask: top.1 });
+        }
+
+        if let Some(published) = &self.published {
+            let levels = |side| self.levels(side).collect();
+            published.store(Arc::new(ImmutableBook {
+                bids: levels(Side::Bid),
+                asks: levels(Side::Ask),
+                last_event_time: self.last_event_time,
+            }));
+        }
+    }
+
+    /// Applies an order to the book, returning whether it removed a price level that was not in
+    /// the book, within the depth of its side.
+    fn apply(&mut self, order: Order<P>) -> bool {
+        self.last_update = Some(Instant::now());
+        let (Order::Bid(details) | Order::Ask(details)) = &order;
+        if let Some(event_time) = details.event_time {
+            if self.last_event_time != Some(event_time) {
+                let latency = latency_us(event_time, SystemTime::now());
+                self.metrics.apply_latency_us.store(latency, AtomicOrdering::Relaxed);
+                #[cfg(feature = "metrics")]
+                record_update_latency(event_time);
+            }
+            self.last_event_time = Some(event_time);
+        }
+        let absent_removal = match order {
+            Order::Bid(OrderDetails { price, quantity, .. }) if quantity == P::default() => {
+                let deepest = self.bids.keys().next_back().map(|Reverse(price)| price.0);
+                self.bids.remove(&Reverse(PriceKey(price))).is_none()
+                    && deepest.is_some_and(|deepest| price > deepest)
+                    && !self.pruned.0.is_some_and(|pruned| price <= pruned)
+            }
+            Order::Bid(OrderDetails { price, quantity, .. }) => {
+                self.bids.insert(Reverse(PriceKey(price)), quantity);
+                self.prune(Side::Bid);
+                false
+            }
+            Order::Ask(OrderDetails { price, quantity, .. }) if quantity == P::default() => {
+                let deepest = self.asks.keys().next_back().map(|price| price.0);
+                self.asks.remove(&PriceKey(price)).is_none()
+                    && deepest.is_some_and(|deepest| price < deepest)
+                    && !self.pruned.1.is_some_and(|pruned| price >= pruned)
+            }
+            Order::Ask(OrderDetails { price, quantity, .. }) => {
+                self.asks.insert(PriceKey(price), quantity);
+                self.prune(Side::Ask);
+                false
+            }
+        };
+        if absent_removal {
+            self.metrics.absent_removals.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        absent_removal
+    }
+
+    /// Applies an order from the connection or `OrderBook::apply`, counting it, as for `apply`.
+    /// Call `finish_orders` once the orders applied together are.
+    fn apply_counted(&mut self, order: Order<P>) -> bool {
+        self.metrics.deltas_applied.fetch_add(1, AtomicOrdering::Relaxed);
+        self.last_set = match &order {
+            Order::Bid(details) if details.quantity != P::default() => Some(Side::Bid),
+            Order::Ask(details) if details.quantity != P::default() => Some(Side::Ask),
+            _ => self.last_set,
+        };
+        self.apply(order)
+    }
+
+    /// Uncrosses the book after orders applied together with `apply_counted`, and publishes it.
+    /// Checked once they all are, as an order may cross the book until the rest of them are
+    /// applied.
+    fn finish_orders(&mut self) {
+        if let Some(side) = self.last_set.take() {
+            self.uncross(side);
+        }
+        self.publish();
+    }
+
+    /// Removes the levels of one side furthest from the top beyond the maximum depth.
+    fn prune(&mut self, side: Side) {
+        match side {
+            Side::Bid => {
+                while self.bids.len() > self.max_depth {
+                    if let Some((Reverse(price), _)) = self.bids.pop_last() {
+                        self.metrics.levels_pruned.fetch_add(1, AtomicOrdering::Relaxed);
+                        let best = self.pruned.0.filter(|&pruned| pruned >= price.0);
+                        self.pruned.0 = Some(best.unwrap_or(price.0));
+                    }
+                }
+            }
+            Side::Ask => {
+                while self.asks.len() > self.max_depth {
+                    if let Some((price, _)) = self.asks.pop_last() {
+                        self.metrics.levels_pruned.fetch_add(1, AtomicOrdering::Relaxed);
+                        let best = self.pruned.1.filter(|&pruned| pruned <= price.0);
+                        self.pruned.1 = Some(best.unwrap_or(price.0));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the levels of the other side at or through the best level of `side`, which was
+    /// just updated. They must be stale, as the exchange's book cannot be crossed.
+    fn uncross(&mut self, side: Side) {
+        let price = match (side, self.top_bid_ask()) {
+            (Side::Bid, (Some(price), _)) | (Side::Ask, (_, Some(price))) => price,
+            _ => return,
+        };
+        let mut removed = 0;
+        match side {
+            Side::Bid => {
+                while let Some(level) = self.asks.first_entry() {
+                    if level.key().0 > price {
+                        break;
+                    }
+                    level.remove();
+                    removed += 1;
+                }
+            }
+            Side::Ask => {
+                while let Some(level) = self.bids.first_entry() {
+                    if level.key().0 .0 < price {
+                        break;
+                    }
+                    level.remove();
+                    removed += 1;
+                }
+            }
+        }
+        if removed > 0 {
+            tracing::warn!(?side, %price, removed, "Book crossed, removed stale levels");
+        }
+    }
+
+    /// Iterates the levels of one side as (price, quantity), best price first.
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = (P, P)> + '_> {
+        match side {
+            Side::Bid => Box::new(self.bids.iter().map(|(Reverse(price), qty)| (price.0, *qty))),
+            Side::Ask => Box::new(self.asks.iter().map(|(price, qty)| (price.0, *qty))),
+        }
+    }
+
+    /// Iterates the levels of one side with at least `min_quantity`, best price first.
+    fn display_levels(
+        &self,
+        side: Side,
+        min_quantity: P,
+    ) -> impl Iterator<Item = (P, P)> + '_ {
+        self.levels(side).filter(move |&(_, quantity)| quantity >= min_quantity)
+    }
+}
+
+impl Book<f64> {
+    /// Returns the total price * quantity and total quantity of the top `levels` levels of a side.
+    fn notional_and_quantity(&self, side: Side, levels: usize) -> (f64, f64) {
+        self.levels(side)
+            .take(levels)
+            .fold((0.0, 0.0), |(notional, quantity), (price, qty)| {
+                (notional + price * qty, quantity + qty)
+            })
+    }
+
+    /// Returns the average price of filling `quantity` against a side, or `None` if the side
+    /// cannot fill it.
+    fn vwap(&self, side: Side, quantity: f64) -> Option<f64> {
+        if quantity <= 0.0 {
+            return None;
+        }
+        let mut remaining = quantity;
+        let mut cost = 0.0;
+        for (price, qty) in self.levels(side) {
+            let filled = remaining.min(qty);
+            cost += filled * price;
+            remaining -= filled;
+            if remaining <= 0.0 {
+                return Some(cost / quantity);
+            }
+        }
+        None
+    }
+}
+
+impl<P: Number> OrderBook<P> {
     /// Create a new order book using the specified connection
-    pub async fn create<C: Connection>(connection: C) -> Self {
-        let book = Arc::new(RwLock::new(Book {
-            bids: BTreeSet::new(),
-            asks: BTreeSet::new(),
-        }));
+    pub async fn create<C: Connection<P>>(connection: C) -> Self {
+        Self::create_with_options(connection, OrderBookOptions::default()).await
+    }
+
+    /// Create a new order book using the specified connection and options
+    pub async fn create_with_options<C: Connection<P>>(
+        connection: C,
+        options: OrderBookOptions<P>,
+    ) -> Self {
+        let empty = BookSnapshot { bids: Vec::new(), asks: Vec::new(), last_event_time: None };
+        Self::start(empty, connection, options)
+    }
+
+    /// Create an order book from the levels of a saved snapshot, e.g. from `to_snapshot`, with
+    /// the connection's deltas applied on top of them. Levels without a positive price are
+    /// dropped. A connection that starts from the
+    /// exchange's own snapshot replaces the saved levels with it instead, as its update ids do
+    /// not continue from the saved ones.
+    pub async fn from_snapshot<C: Connection<P>>(snapshot: BookSnapshot<P>, connection: C) -> Self {
+        Self::from_snapshot_with_options(snapshot, connection, OrderBookOptions::default()).await
+    }
 
+    /// Create an order book from the levels of a saved snapshot, using the specified connection
+    /// and options
+    pub async fn from_snapshot_with_options<C: Connection<P>>(
+        snapshot: BookSnapshot<P>,
+        connection: C,
+        options: OrderBookOptions<P>,
+    ) -> Self {
+        Self::start(snapshot, connection, options)
+    }
+
+    /// Creates the book with the levels of `initial`, and starts its background tasks.
+    fn start<C: Connection<P>>(
+        initial: BookSnapshot<P>,
+        connection: C,
+        options: OrderBookOptions<P>,
+    ) -> Self {
+        let snapshot = Arc::new(ArcSwap::from_pointee(ImmutableBook::default()));
+        let updates = broadcast::channel(UPDATES_CAPACITY).0;
+        let metrics = Arc::new(BookMetrics::default());
+        let valid = |&(price, _): &(P, P)| {
+            let valid = is_valid_price(price);
+            if !valid {
+                tracing::warn!(%price, "Dropping saved level with invalid price");
+            }
+            valid
+        };
+        let bids = initial.bids.into_iter().filter(valid);
+        let asks = initial.asks.into_iter().filter(valid);
+        let mut book = Book {
+            bids: bids.map(|(p, q)| (Reverse(PriceKey(p)), q)).collect(),
+            asks: asks.map(|(p, q)| (PriceKey(p), q)).collect(),
+            last_update: None,
+            last_event_time: initial.last_event_time,
+            published: options.lock_free_snapshot.then(|| snapshot.clone()),
+            updates: updates.clone(),
+            published_top: (None, None),
+            max_depth: options.max_depth.map_or(usize::MAX, |depth| depth.max(1)),
+            pruned: (None, None),
+            last_set: None,
+            metrics: metrics.clone(),
+        };
+        book.prune(Side::Bid);
+        book.prune(Side::Ask);
+        book.publish();
+        let book = Arc::new(RwLock::new(book));
+
+        let reconnect_callbacks = Arc::new(Mutex::new(Vec::new()));
+        // Registered before the update task starts, so it sees errors of the first connection.
+        let mut error_callbacks: Vec<ErrorCallback> = Vec::new();
+        if let Some(on_error) = options.on_error.clone() {
+            error_callbacks.push(Box::new(move |e| on_error(e)));
+        }
+        let error_callbacks = Arc::new(Mutex::new(error_callbacks));
+        let (state_sender, state) = watch::channel(BookState::Connecting);
+        let shutdown = CancellationToken::new();
         let book_clone = book.clone();
-        tokio::spawn(order_book_process(book_clone, connection));
+        let process = order_book_process(
+            book_clone,
+            connection,
+            options.clone(),
+            reconnect_callbacks.clone(),
+            error_callbacks.clone(),
+            metrics.clone(),
+            state_sender,
+        );
+        let mut tasks = vec![tokio::spawn(until_shutdown(shutdown.clone(), process))];
+        if let Some(interval) = options.heartbeat_interval {
+            let heartbeat = heartbeat_process(book.clone(), interval);
+            tasks.push(tokio::spawn(until_shutdown(shutdown.clone(), heartbeat)));
+        }
         Self {
-            book
+            book,
+            snapshot,
+            reconnect_callbacks,
+            error_callbacks,
+            metrics,
+            updates,
+            state,
+            min_display_quantity: options.min_display_quantity.unwrap_or_default(),
+            options,
+            shutdown,
+            tasks,
+        }
+    }
+
+    /// Stops the background tasks, closing the connection, and waits for them to finish.
+    /// Dropping the book also stops them, without waiting.
+    pub async fn shutdown(mut self) {
+        self.shutdown.cancel();
+        for task in std::mem::take(&mut self.tasks) {
+            // A panicked task has stopped too.
+            let _ = task.await;
         }
     }
 
+    /// Registers a callback invoked each time the book reconnects after losing its connection.
+    /// The callback runs on the book's update task, so must not block.
+    pub fn on_reconnect(&self, f: impl Fn(&ReconnectInfo) + Send + Sync + 'static) {
+        self.reconnect_callbacks.lock().unwrap().push(Box::new(f));
+    }
+
+    /// Registers a callback invoked for each error connecting or from the connection's stream,
+    /// before the book handles it, e.g. to drive alerts. The callback runs on the book's update
+    /// task, so must not block. Errors before it is registered are missed; set
+    /// `OrderBookOptions::on_error` to see them too.
+    pub fn on_error(&self, f: impl Fn(&Error) + Send + Sync + 'static) {
+        self.error_callbacks.lock().unwrap().push(Box::new(f));
+    }
+
+    /// Applies an order to the book, as if received from the connection. Fails with
+    /// `Error::InvalidPrice` if the price is not positive, e.g. NaN.
+    pub async fn apply(&self, order: Order<P>) -> Result<(), Error> {
+        check_price(&order)?;
+        let mut book = self.book.write().await;
+        book.apply_counted(order);
+        book.finish_orders();
+        Ok(())
+    }
+
+    /// Applies a batch of orders under a single write lock, so readers see either none or all
+    /// of them. Fails with `Error::InvalidPrice`, applying none of them, if any price is not
+    /// positive.
+    pub async fn apply_batch(&self, orders: Vec<Order<P>>) -> Result<(), Error> {
+        orders.iter().try_for_each(check_price)?;
+        let mut book = self.book.write().await;
+        for order in orders {
+            book.apply_counted(order);
+        }
+        book.finish_orders();
+        Ok(())
+    }
+
     /// Returns the (possibly empty) top bid and ask from the book.
-    pub async fn top_bid_ask(&self) -> (Option<f32>, Option<f32>) {
+    pub async fn top_bid_ask(&self) -> (Option<P>, Option<P>) {
+        self.book.read().await.top_bid_ask()
+    }
+
+    /// Returns the (possibly empty) top bid from the book.
+    pub async fn best_bid(&self) -> Option<P> {
+        self.book.read().await.bids.keys().next().map(|Reverse(price)| price.0)
+    }
+
+    /// Returns the (possibly empty) top ask from the book.
+    pub async fn best_ask(&self) -> Option<P> {
+        self.book.read().await.asks.keys().next().map(|price| price.0)
+    }
+
+    /// Returns a receiver of changes of the top of book, sent after each batch of updates that
+    /// changes it. A receiver that falls more than 1024 changes behind gets a `Lagged` error
+    /// and then skips to the oldest change kept.
+    pub fn subscribe(&self) -> broadcast::Receiver<BookUpdate<P>> {
+        self.updates.subscribe()
+    }
+
+    /// Returns the current state of the book's connection.
+    pub fn state(&self) -> BookState {
+        *self.state.borrow()
+    }
+
+    /// Returns the counts of the book's events so far, e.g. to export to a metrics system.
+    pub fn metrics(&self) -> BookMetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Returns true if the book gave up reconnecting, and is no longer updated.
+    pub fn has_failed(&self) -> bool {
+        self.state() == BookState::Failed
+    }
+
+    /// Returns a receiver of the state of the book's connection, which is notified of changes.
+    pub fn watch_state(&self) -> watch::Receiver<BookState> {
+        self.state.clone()
+    }
+
+    /// Returns the latest immutable copy of the book without taking a lock. The copy is only
+    /// published when `OrderBookOptions::lock_free_snapshot` is set, and is empty otherwise.
+    pub fn snapshot_fast(&self) -> Arc<ImmutableBook<P>> {
+        self.snapshot.load_full()
+    }
+
+    /// Returns the (possibly empty) top bid and ask from the book, with the exchange time of the
+    /// latest applied update.
+    pub async fn top_bid_ask_timed(&self) -> (Option<P>, Option<P>, Option<ExchangeTime>) {
         let book = self.book.read().await;
-        (
-            book.bids.first().map(|order| order.0.price),
-            book.asks.first().map(|order| order.price),
-        )
+        let (bid, ask) = book.top_bid_ask();
+        (bid, ask, book.last_event_time)
+    }
+
+    /// Returns the (possibly empty) top bid and ask from the book as (price, quantity).
+    pub async fn top_bid_ask_qty(&self) -> (Option<(P, P)>, Option<(P, P)>) {
+        let book = self.book.read().await;
+        let top = (book.levels(Side::Bid).next(), book.levels(Side::Ask).next());
+        top
+    }
+
+    /// Returns the side present if the book only has levels on one side, or `None` if it has
+    /// both or neither.
+    pub async fn one_sided(&self) -> Option<Side> {
+        let book = self.book.read().await;
+        match (book.bids.is_empty(), book.asks.is_empty()) {
+            (false, true) => Some(Side::Bid),
+            (true, false) => Some(Side::Ask),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the best bid is at or above the best ask. Levels crossed by an update are
+    /// removed as it is applied, so this should not happen.
+    pub async fn is_crossed(&self) -> bool {
+        match self.book.read().await.top_bid_ask() {
+            (Some(bid), Some(ask)) => bid >= ask,
+            _ => false,
+        }
+    }
+
+    /// Returns the time elapsed since an order was last applied to the book, if any has been.
+    pub async fn last_update_age(&self) -> Option<Duration> {
+        self.book.read().await.last_update.map(|instant| instant.elapsed())
+    }
+
+    /// Returns the exchange time of the latest applied update, in milliseconds since the Unix
+    /// epoch, if known.
+    pub async fn last_update_time(&self) -> Option<u64> {
+        self.book.read().await.last_event_time.map(|time| time.as_millis())
+    }
+
+    /// Returns true if the exchange time of the latest applied update is more than `max_age_ms`
+    /// before `now_ms`, or unknown.
+    pub async fn is_stale(&self, max_age_ms: u64, now_ms: u64) -> bool {
+        match self.last_update_time().await {
+            Some(time) => now_ms.saturating_sub(time) > max_age_ms,
+            None => true,
+        }
+    }
+
+    /// Returns the number of (bid, ask) price levels, e.g. to alert when a side thins out.
+    pub async fn level_counts(&self) -> (usize, usize) {
+        let book = self.book.read().await;
+        (book.bids.len(), book.asks.len())
+    }
+
+    /// Returns the total number of removals of price levels that were not in the book, within
+    /// the depth of their side. A growing count suggests the book missed the corresponding
+    /// inserts.
+    pub async fn absent_removals(&self) -> u64 {
+        self.metrics.absent_removals.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Returns the book's internal state and options as one serializable value, for bug
+    /// reports.
+    pub async fn debug_dump(&self) -> DebugDump<P> {
+        let book = self.book.read().await;
+        let levels = |side| book.levels(side).collect();
+        DebugDump {
+            bids: levels(Side::Bid),
+            asks: levels(Side::Ask),
+            last_event_time: book.last_event_time,
+            last_update_age: book.last_update.map(|instant| instant.elapsed()),
+            state: self.state(),
+            metrics: self.metrics(),
+            options: self.options.clone(),
+        }
+    }
+
+    /// Returns all levels of the book, bids descending and asks ascending, as one serializable
+    /// value.
+    pub async fn to_snapshot(&self) -> BookSnapshot<P> {
+        let book = self.book.read().await;
+        let levels = |side| book.levels(side).collect();
+        BookSnapshot {
+            bids: levels(Side::Bid),
+            asks: levels(Side::Ask),
+            last_event_time: book.last_event_time,
+        }
+    }
+
+    /// Returns the top `n` levels of each side as (price, quantity), best price first: bids
+    /// descending and asks ascending. Levels below the minimum display quantity are skipped.
+    pub async fn depth(&self, n: usize) -> (Vec<(P, P)>, Vec<(P, P)>) {
+        let book = self.book.read().await;
+        let levels = |side| {
+            book.display_levels(side, self.min_display_quantity)
+                .take(n)
+                .collect()
+        };
+        (levels(Side::Bid), levels(Side::Ask))
+    }
+
+    /// Returns the top `n` levels of each side as parallel arrays of bid prices, bid quantities,
+    /// ask prices and ask quantities, best price first. Levels below the minimum display
+    /// quantity are skipped.
+    pub async fn depth_columnar(&self, n: usize) -> (Vec<P>, Vec<P>, Vec<P>, Vec<P>) {
+        let book = self.book.read().await;
+        let (bid_prices, bid_qtys) = book
+            .display_levels(Side::Bid, self.min_display_quantity)
+            .take(n)
+            .unzip();
+        let (ask_prices, ask_qtys) = book
+            .display_levels(Side::Ask, self.min_display_quantity)
+            .take(n)
+            .unzip();
+        (bid_prices, bid_qtys, ask_prices, ask_qtys)
+    }
+
+    /// Renders the top `n` levels of each side as a price ladder, one level per line in
+    /// descending price order: asks above a separator line, bids below it. Prices and quantities
+    /// are right-aligned in columns. Levels below the minimum display quantity are skipped.
+    pub async fn ladder(&self, n: usize) -> String {
+        let book = self.book.read().await;
+        let format = |(price, qty): (P, P)| (price.to_string(), qty.to_string());
+        let mut asks: Vec<_> = book
+            .display_levels(Side::Ask, self.min_display_quantity)
+            .take(n)
+            .map(format)
+            .collect();
+        asks.reverse();
+        let bids: Vec<_> = book
+            .display_levels(Side::Bid, self.min_display_quantity)
+            .take(n)
+            .map(format)
+            .collect();
+        drop(book);
+
+        let rows = || asks.iter().chain(&bids);
+        let price_width = rows().map(|(price, _)| price.len()).max().unwrap_or(0);
+        let qty_width = rows().map(|(_, qty)| qty.len()).max().unwrap_or(0);
+        let line = |side, (price, qty): &(String, String)| {
+            format!("{} {:>pw$} {:>qw$}\n", side, price, qty, pw = price_width, qw = qty_width)
+        };
+
+        let mut ladder = String::new();
+        for row in &asks {
+            ladder.push_str(&line("ask", row));
+        }
+        ladder.push_str(&"-".repeat(5 + price_width + qty_width));
+        ladder.push('\n');
+        for row in &bids {
+            ladder.push_str(&line("bid", row));
+        }
+        ladder
+    }
+}
+
+impl OrderBook {
+    /// Returns the midpoint of the top bid and ask, or `None` if either side is empty.
+    pub async fn mid_price(&self) -> Option<f64> {
+        let (bid, ask) = self.top_bid_ask().await;
+        Some((bid? + ask?) / 2.0)
+    }
+
+    /// Returns the top ask minus the top bid, or `None` if either side is empty. A crossed book
+    /// gives a negative spread.
+    pub async fn spread(&self) -> Option<f64> {
+        let (bid, ask) = self.top_bid_ask().await;
+        Some(ask? - bid?)
+    }
+
+    /// Returns the depth-weighted mid over the top `levels` levels of each side: the quantity
+    /// weighted average price of each side, weighted in turn by that side's cumulative quantity.
+    /// Returns `None` if either side has no levels.
+    pub async fn weighted_mid(&self, levels: usize) -> Option<f64> {
+        let book = self.book.read().await;
+        let (bid_notional, bid_qty) = book.notional_and_quantity(Side::Bid, levels);
+        let (ask_notional, ask_qty) = book.notional_and_quantity(Side::Ask, levels);
+        if bid_qty <= 0.0 || ask_qty <= 0.0 {
+            return None;
+        }
+        Some((bid_notional + ask_notional) / (bid_qty + ask_qty))
+    }
+
+    /// Returns the quantity imbalance over the top `levels` levels of each side, from -1.0 (all
+    /// asks) to 1.0 (all bids). Returns `None` if either side has no levels.
+    pub async fn imbalance(&self, levels: usize) -> Option<f64> {
+        let book = self.book.read().await;
+        let (_, bid_qty) = book.notional_and_quantity(Side::Bid, levels);
+        let (_, ask_qty) = book.notional_and_quantity(Side::Ask, levels);
+        if bid_qty <= 0.0 || ask_qty <= 0.0 {
+            return None;
+        }
+        Some((bid_qty - ask_qty) / (bid_qty + ask_qty))
+    }
+
+    /// Returns the volume-weighted average price of filling `target_qty` against a side, counting
+    /// only the quantity needed from the last level, or `None` if the side cannot fill it.
+    pub async fn vwap(&self, side: Side, target_qty: f64) -> Option<f64> {
+        self.book.read().await.vwap(side, target_qty)
+    }
+
+    /// Returns the effective spread of filling `quantity` against a side: twice the difference
+    /// between the average fill price and the mid price, signed so that a cost is positive.
+    /// Returns `None` if the side cannot fill the quantity or the book is one-sided.
+    pub async fn effective_spread(&self, side: Side, quantity: f64) -> Option<f64> {
+        let book = self.book.read().await;
+        let (bid, ask) = book.top_bid_ask();
+        let mid = (bid? + ask?) / 2.0;
+        let vwap = book.vwap(side, quantity)?;
+        match side {
+            Side::Ask => Some(2.0 * (vwap - mid)),
+            Side::Bid => Some(2.0 * (mid - vwap)),
+        }
+    }
+
+    /// Returns (cumulative quantity, average fill price) points for filling up to `max_qty`
+    /// against the given side, at `steps` evenly spaced quantities. If the side cannot fill
+    /// `max_qty`, the curve stops with a final point at the available depth.
+    pub async fn fill_curve(&self, side: Side, max_qty: f64, steps: usize) -> Vec<(f64, f64)> {
+        let mut curve = Vec::with_capacity(steps);
+        if steps == 0 || max_qty <= 0.0 {
+            return curve;
+        }
+
+        let step = max_qty / steps as f64;
+        let book = self.book.read().await;
+        let mut filled = 0.0;
+        let mut cost = 0.0;
+        let mut next = 1;
+        for (price, qty) in book.levels(side) {
+            let level_end = filled + qty;
+            while next <= steps {
+                let target = if next == steps { max_qty } else { step * next as f64 };
+                if target > level_end {
+                    break;
+                }
+                let target_cost = cost + (target - filled) * price;
+                curve.push((target, target_cost / target));
+                next += 1;
+            }
+            if next > steps {
+                return curve;
+            }
+            filled = level_end;
+            cost += qty * price;
+        }
+
+        // Could not fill max_qty, end the curve at the available depth.
+        if filled > 0.0 && curve.last().is_none_or(|(qty, _)| *qty < filled) {
+            curve.push((filled, cost / filled));
+        }
+        curve
+    }
+
+    /// Returns the Herfindahl index of the quantity distribution over the top `levels` levels of
+    /// a side: the sum of squared quantity shares, from `1 / levels` for evenly spread liquidity
+    /// to 1 for liquidity concentrated in a single level. Returns `None` if the side has fewer
+    /// than `levels` levels or no quantity.
+    pub async fn concentration(&self, side: Side, levels: usize) -> Option<f64> {
+        if levels == 0 {
+            return None;
+        }
+        let book = self.book.read().await;
+        let quantities: Vec<f64> = book.levels(side).take(levels).map(|(_, qty)| qty).collect();
+        let total: f64 = quantities.iter().sum();
+        if quantities.len() < levels || total <= 0.0 {
+            return None;
+        }
+        Some(quantities.iter().map(|q| (q / total) * (q / total)).sum())
+    }
+
+    /// Returns the total quantity of bids at or above `price`.
+    pub async fn bid_volume_above(&self, price: f64) -> f64 {
+        let book = self.book.read().await;
+        let levels = book.levels(Side::Bid).take_while(|&(bid, _)| bid >= price);
+        levels.map(|(_, qty)| qty).sum()
+    }
+
+    /// Returns the total quantity of asks at or below `price`.
+    pub async fn ask_volume_below(&self, price: f64) -> f64 {
+        let book = self.book.read().await;
+        let levels = book.levels(Side::Ask).take_while(|&(ask, _)| ask <= price);
+        levels.map(|(_, qty)| qty).sum()
+    }
+}
+
+/// Records the latency from an update's exchange event time to it being applied, in seconds,
+/// to the `order_book_update_latency_seconds` histogram (see `OrderBook`).
+#[cfg(feature = "metrics")]
+fn record_update_latency(event_time: ExchangeTime) {
+    let latency = SystemTime::now()
+        .duration_since(event_time.to_system_time())
+        .unwrap_or(Duration::ZERO);
+    metrics::histogram!("order_book_update_latency_seconds").record(latency.as_secs_f64());
+}
+
+impl<P> Drop for OrderBook<P> {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Runs `task` until it completes or `shutdown` is cancelled.
+async fn until_shutdown(shutdown: CancellationToken, task: impl Future<Output = ()>) {
+    tokio::select! {
+        _ = shutdown.cancelled() => {}
+        _ = task => {}
     }
 }
 
-/// Order book update process - loops indefinitely, recreating the connection stream on error.
-async fn order_book_process<C: Connection>(book: Arc<RwLock<Book>>, connection: C) {
+/// Heartbeat process - logs the top of book and last update age at the given interval.
+async fn heartbeat_process<P: Number>(book: Arc<RwLock<Book<P>>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
     loop {
-        if let Ok(mut stream) = connection.stream().await {
-            while let Some(result) = stream.next().await {
-                match result {
-                    Ok(order) => {
-                        let mut book = book.write().await;
-                        match order {
-                            Order::Bid(details) if details.quantity == 0.0 => {
-                                book.bids.remove(&Reverse(details));
-                            }
-                            Order::Bid(details) => {
-                                book.bids.insert(Reverse(details));
-                            },
-                            Order::Ask(details) if details.quantity == 0.0 => {
-                                book.asks.remove(&details);
-                            },
-                            Order::Ask(details) => {
-                                book.asks.insert(details);
-                            },
-                        }
+        ticker.tick().await;
+        let book = book.read().await;
+        let (bid, ask) = book.top_bid_ask();
+        let age_ms = book.last_update.map(|instant| instant.elapsed().as_millis());
+        tracing::info!(?bid, ?ask, ?age_ms, "Order book heartbeat");
+    }
+}
 
+/// Order book update process - loops until out of connection attempts, recreating the
+/// connection stream on error.
+async fn order_book_process<P: Number, C: Connection<P>>(
+    book: Arc<RwLock<Book<P>>>,
+    connection: C,
+    options: OrderBookOptions<P>,
+    reconnect_callbacks: Arc<Mutex<Vec<ReconnectCallback>>>,
+    error_callbacks: Arc<Mutex<Vec<ErrorCallback>>>,
+    metrics: Arc<BookMetrics>,
+    state: watch::Sender<BookState>,
+) {
+    let absent_removal_threshold = options.absent_removal_threshold.unwrap_or(u64::MAX);
+    let mut resync = false;
+    let mut disconnected: Option<Instant> = None;
+    let mut attempt = 0;
+    let mut last_error = None;
+    let mut backoff = Backoff::default();
+    loop {
+        let span = tracing::info_span!("connect", resync, outcome = tracing::field::Empty);
+        let result = connection.stream().instrument(span.clone()).await;
+        span.record("outcome", if result.is_ok() { "ok" } else { "error" });
+        resync = true;
+        attempt += 1;
+
+        match result {
+            Ok(stream) => {
+                set_state(&state, BookState::Live);
+                if let Some(disconnected) = disconnected.take() {
+                    metrics.reconnects.fetch_add(1, AtomicOrdering::Relaxed);
+                    let info = ReconnectInfo {
+                        attempt,
+                        last_error: last_error.as_ref(),
+                        downtime: disconnected.elapsed(),
+                    };
+                    for callback in reconnect_callbacks.lock().unwrap().iter() {
+                        callback(&info);
                     }
-                    Err(e) => {
-                        // TODO: proper logging.
-                        println!("Error consuming order stream: {:?}", e);
-
-                        // Clear order book to prevent use of stale values.
-                        // TODO: is this correct behaviour?
-                        let mut order_book = book.write().await;
-                        order_book.bids.clear();
-                        order_book.asks.clear();
+                }
+                attempt = 0;
+                backoff.reset();
+                // On the clock of the timer ending the stream for a scheduled resync.
+                let connected = tokio::time::Instant::now();
+                let stream = match options.resync_interval {
+                    Some(interval) => Box::pin(stream.take_until(tokio::time::sleep(interval))),
+                    None => stream,
+                };
+                last_error = consume_stream(
+                    &book,
+                    stream,
+                    connection.starts_with_snapshot(),
+                    &options,
+                    absent_removal_threshold,
+                    &error_callbacks,
+                    &state,
+                )
+                .await;
+                // Levels kept until a resync replaces them stay marked stale.
+                if *state.borrow() != BookState::Stale {
+                    set_state(&state, BookState::Reconnecting);
+                }
+
+                // A scheduled resync reconnects at once. The new stream starts from a snapshot,
+                // which replaces the book in its first write, so no updates are lost.
+                let resync_due =
+                    options.resync_interval.is_some_and(|interval| connected.elapsed() >= interval);
+                if resync_due && !last_error.as_ref().is_some_and(Error::is_fatal) {
+                    tracing::info!("Resyncing from a fresh snapshot");
+                    continue;
+                }
+            }
+            Err(e) => {
+                notify_error(&error_callbacks, &metrics, &e);
+                if options.max_reconnect_attempts.is_some_and(|max| attempt >= max) {
+                    tracing::error!(attempt, error = %e, "Connection failed, giving up");
+                    let mut book = book.write().await;
+                    book.clear();
+                    book.publish();
+                    set_state(&state, BookState::Failed);
+                    return;
+                }
+                last_error = Some(e);
+            }
+        }
+        disconnected.get_or_insert_with(Instant::now);
+
+        // Reconnecting during maintenance is futile, so retry slowly until the window ends. The
+        // book keeps its levels, marked stale.
+        let now = SystemTime::now();
+        if options.maintenance_windows.iter().any(|window| window.contains(now)) {
+            tracing::info!(retry_in = ?MAINTENANCE_RETRY_INTERVAL, "In maintenance window");
+            set_state(&state, BookState::Stale);
+            tokio::time::sleep(MAINTENANCE_RETRY_INTERVAL).await;
+        } else {
+            // Wait at least as long as the exchange asked, if rate limited.
+            let retry_after = last_error.as_ref().and_then(Error::retry_after);
+            let delay = backoff.next_delay().max(retry_after.unwrap_or_default());
+            tracing::warn!(attempt, retry_in = ?delay, "Disconnected, reconnecting");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Exponential backoff between reconnection attempts, with jitter so that many books do not
+/// reconnect in lockstep.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: RECONNECT_BACKOFF_INITIAL,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns a random delay between half and all of the current delay, and doubles the
+    /// current delay up to the maximum.
+    fn next_delay(&mut self) -> Duration {
+        // Randomly seeded hasher, to avoid a dependency for a little randomness.
+        let random = RandomState::new().build_hasher().finish();
+        let jitter = 0.5 + 0.5 * (random as f64 / u64::MAX as f64);
+        let delay = self.delay.mul_f64(jitter);
+        self.delay = (self.delay * 2).min(RECONNECT_BACKOFF_MAX);
+        delay
+    }
+
+    /// Restarts from the initial delay, after a successful connection.
+    fn reset(&mut self) {
+        self.delay = RECONNECT_BACKOFF_INITIAL;
+    }
+}
+
+/// Applies orders from the stream to the book until it ends or the book needs resyncing, and
+/// returns the last stream error, if any.
+async fn consume_stream<P: Number>(
+    book: &RwLock<Book<P>>,
+    mut stream: ConnectionStream<P>,
+    starts_with_snapshot: bool,
+    options: &OrderBookOptions<P>,
+    absent_removal_threshold: u64,
+    error_callbacks: &Mutex<Vec<ErrorCallback>>,
+    state: &watch::Sender<BookState>,
+) -> Option<Error> {
+    let mut last_error = None;
+    let mut absent_removals = 0;
+    let mut replace_book = starts_with_snapshot;
+    while let Some(first) = next_item(&mut stream, options.stale_after, state).await {
+        let batch = next_batch(&mut stream, first, options.coalesce_window).await;
+        set_state(state, BookState::Live);
+        let mut book = book.write().await;
+        if replace_book {
+            // A new stream starts from a snapshot, which replaces the book as of the previous
+            // stream in the same write.
+            book.clear();
+            replace_book = false;
+        }
+        let mut reconnect = false;
+        let batch_len = batch.len();
+        for result in batch {
+            // Connections from outside this crate may not validate prices.
+            let result = result.and_then(|order| check_price(&order).map(|()| order));
+            match result {
+                Ok(order) => {
+                    if book.apply_counted(order) {
+                        absent_removals += 1;
+                        if absent_removals >= absent_removal_threshold {
+                            tracing::warn!(absent_removals, "Absent levels removed, resyncing");
+
+                            // Drop the stream to reconnect from a fresh snapshot, which replaces
+                            // the levels. Until then, they are kept but marked stale.
+                            set_state(state, BookState::Stale);
+                            reconnect = true;
+                            break;
+                        }
                     }
                 }
+                Err(e) if !e.is_fatal() => {
+                    notify_error(error_callbacks, &book.metrics, &e);
+
+                    // Only the offending message was lost, so keep the book.
+                    tracing::warn!(error = %e, "Skipping invalid message");
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    notify_error(error_callbacks, &book.metrics, &e);
+                    tracing::error!(error = %e, "Error consuming order stream");
+
+                    // The book may have missed updates, so clear it to prevent use of stale
+                    // values and reconnect from a fresh snapshot.
+                    book.clear();
+                    last_error = Some(e);
+                    reconnect = true;
+                    break;
+                }
+            }
+        }
+        book.finish_orders();
+        tracing::debug!(orders = batch_len, "Applied batch");
+        if reconnect {
+            break;
+        }
+    }
+    last_error
+}
+
+/// Waits for the next stream item, marking the book stale if none arrives within `stale_after`.
+/// Returns `None` when the stream ends.
+async fn next_item<P>(
+    stream: &mut ConnectionStream<P>,
+    stale_after: Option<Duration>,
+    state: &watch::Sender<BookState>,
+) -> Option<Result<Order<P>, Error>> {
+    if let Some(stale_after) = stale_after {
+        match tokio::time::timeout(stale_after, stream.next()).await {
+            Ok(item) => return item,
+            Err(_) => set_state(state, BookState::Stale),
+        }
+    }
+    stream.next().await
+}
+
+/// Collects any items following `first` that are already available, so orders from the same
+/// exchange update are applied together. If a coalescing window is set, also collects items
+/// received within the window.
+async fn next_batch<P>(
+    stream: &mut ConnectionStream<P>,
+    first: Result<Order<P>, Error>,
+    window: Option<Duration>,
+) -> Vec<Result<Order<P>, Error>> {
+    let mut batch = vec![first];
+    while batch.len() < MAX_READY_BATCH {
+        match stream.next().now_or_never() {
+            Some(Some(result)) => batch.push(result),
+            _ => break,
+        }
+    }
+    if let Some(window) = window {
+        let deadline = tokio::time::sleep(window);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                result = stream.next() => match result {
+                    Some(result) => batch.push(result),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+    }
+    batch
+}
+
+/// Returns true if `price` is positive, so not NaN either.
+fn is_valid_price<P: Number>(price: P) -> bool {
+    price > P::default()
+}
+
+/// Fails with `Error::InvalidPrice` if the order's price is not positive, as it would corrupt
+/// the book's ordering if NaN.
+fn check_price<P: Number>(order: &Order<P>) -> Result<(), Error> {
+    let (Order::Bid(details) | Order::Ask(details)) = order;
+    if !is_valid_price(details.price) {
+        return Err(Error::InvalidPrice(details.price.to_string()));
+    }
+    Ok(())
+}
+
+/// Counts `e` and invokes the error callbacks with it.
+fn notify_error(error_callbacks: &Mutex<Vec<ErrorCallback>>, metrics: &BookMetrics, e: &Error) {
+    metrics.errors.fetch_add(1, AtomicOrdering::Relaxed);
+    for callback in error_callbacks.lock().unwrap().iter() {
+        callback(e);
+    }
+}
+
+/// Publishes a change of the book's state. Unchanged states are not published.
+fn set_state(state: &watch::Sender<BookState>, new: BookState) {
+    state.send_if_modified(|current| std::mem::replace(current, new) != new);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use async_trait::async_trait;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::connection::MockConnection;
+
+    /// Outcome of connecting: the stream's items, or the connection error.
+    type Outcome = Result<Vec<Result<Order, Error>>, Error>;
+
+    /// A connection playing one scripted outcome per call to `stream`: failing to connect, or
+    /// streaming items and then staying open. Streams stay open without items once the script
+    /// runs out. Records the time of each call.
+    struct ScriptedConnection {
+        script: Mutex<VecDeque<Outcome>>,
+        calls: Arc<Mutex<Vec<tokio::time::Instant>>>,
+    }
+
+    impl ScriptedConnection {
+        fn new(script: Vec<Outcome>) -> Self {
+            Self { script: Mutex::new(script.into()), calls: Default::default() }
+        }
+    }
+
+    #[async_trait]
+    impl Connection for ScriptedConnection {
+        async fn stream(&self) -> Result<ConnectionStream, Error> {
+            self.calls.lock().unwrap().push(tokio::time::Instant::now());
+            let items = self.script.lock().unwrap().pop_front().unwrap_or(Ok(Vec::new()))?;
+            Ok(Box::pin(futures::stream::iter(items).chain(futures::stream::pending())))
+        }
+    }
+
+    fn closed() -> Error {
+        Error::StreamClosed { code: 1000, reason: String::new() }
+    }
+
+    fn refused() -> Error {
+        Error::UnexpectedItem("Connection refused".to_owned())
+    }
+
+    fn bid(price: f64, quantity: f64) -> Result<Order, Error> {
+        Ok(Order::Bid(OrderDetails { price, quantity, event_time: None }))
+    }
+
+    fn ask(price: f64, quantity: f64) -> Result<Order, Error> {
+        Ok(Order::Ask(OrderDetails { price, quantity, event_time: None }))
+    }
+
+    /// Lets the book apply the connection's items. Tests run with the clock paused, which only
+    /// advances once every task is idle, so this returns after the book has applied them.
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    /// Creates a book holding the given orders.
+    async fn book_with(orders: Vec<Result<Order, Error>>) -> OrderBook {
+        let book = OrderBook::create(MockConnection::new(orders)).await;
+        settle().await;
+        book
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("no value");
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn stream_errors_are_logged() {
+        let book: OrderBook = OrderBook::create(MockConnection::new(vec![Err(closed())])).await;
+        book.watch_state().wait_for(|s| *s == BookState::Reconnecting).await.unwrap();
+
+        assert!(logs_contain("Error consuming order stream"));
+        assert!(logs_contain("code 1000"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_error_keeps_the_book() {
+        let parse_error = Err(Error::ParseNumber("1.2.3".to_owned()));
+        let items = vec![bid(100.0, 1.0), ask(101.0, 2.0), parse_error, bid(99.0, 3.0)];
+        let book = OrderBook::create(MockConnection::new(items)).await;
+        settle().await;
+
+        assert_eq!(book.depth(5).await, (vec![(100.0, 1.0), (99.0, 3.0)], vec![(101.0, 2.0)]));
+        assert_eq!(book.metrics().errors, 1);
+        assert_eq!(book.metrics().reconnects, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deltas_apply_on_top_of_a_saved_snapshot() {
+        let snapshot = BookSnapshot {
+            bids: vec![(100.0, 1.0), (99.0, 1.0)],
+            asks: vec![(101.0, 1.0), (102.0, 1.0)],
+            last_event_time: None,
+        };
+        let deltas = vec![bid(99.0, 5.0), bid(98.0, 2.0), ask(101.0, 0.0)];
+        let book = OrderBook::from_snapshot(snapshot, MockConnection::new(deltas)).await;
+        settle().await;
+
+        let merged = book.to_snapshot().await;
+        assert_eq!(merged.bids, [(100.0, 1.0), (99.0, 5.0), (98.0, 2.0)]);
+        assert_eq!(merged.asks, [(102.0, 1.0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removals_beyond_the_deepest_level_are_not_absent() {
+        let items = vec![
+            bid(100.0, 1.0),
+            bid(99.0, 1.0),
+            ask(101.0, 1.0),
+            ask(102.0, 1.0),
+            bid(50.0, 0.0),
+            ask(200.0, 0.0),
+            bid(99.5, 0.0),
+            ask(101.5, 0.0),
+        ];
+        let book = OrderBook::create(MockConnection::new(items)).await;
+        settle().await;
+
+        assert_eq!(book.metrics().absent_removals, 2);
+        assert_eq!(book.absent_removals().await, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn absent_removals_past_the_threshold_resync() {
+        let options = OrderBookOptions { absent_removal_threshold: Some(2), ..Default::default() };
+        let healthy = || {
+            let mut items = vec![bid(100.0, 1.0), bid(99.0, 1.0), ask(101.0, 1.0)];
+            // Beyond the deepest bid, so not counted.
+            items.extend((0..10).map(|i| bid(90.0 - i as f64, 0.0)));
+            items
+        };
+        let connection = MockConnection::new(healthy());
+        let book = OrderBook::create_with_options(connection, options.clone()).await;
+        settle().await;
+        assert_eq!(book.level_counts().await, (2, 1));
+
+        // Resynced from a new stream's snapshot, keeping the stale levels until it replaces them.
+        let mut items = healthy();
+        items.extend([bid(99.5, 0.0), bid(99.7, 0.0)]);
+        let resynced = vec![bid(98.0, 1.0), ask(102.0, 1.0)];
+        let connection = ScriptedConnection::new(vec![Ok(items), Ok(resynced)]);
+        let book = OrderBook::create_with_options(connection, options).await;
+        settle().await;
+        assert_eq!(book.metrics().absent_removals, 2);
+        assert_eq!(book.state(), BookState::Stale);
+        assert_eq!(book.top_bid_ask().await, (Some(100.0), Some(101.0)));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(book.state(), BookState::Live);
+        assert_eq!(book.top_bid_ask().await, (Some(98.0), Some(102.0)));
+        assert_eq!(book.level_counts().await, (1, 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn readers_see_all_or_none_of_a_batch() {
+        let book = Arc::new(OrderBook::create(MockConnection::new(Vec::new())).await);
+        let reader = tokio::spawn({
+            let book = book.clone();
+            async move {
+                for _ in 0..10_000 {
+                    let (bids, _) = book.level_counts().await;
+                    assert!(bids == 0 || bids == 50, "{bids} bids");
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        let batch = (1..=50).map(|price| bid(price as f64, 1.0).unwrap()).collect();
+        book.apply_batch(batch).await.unwrap();
+        reader.await.unwrap();
+        assert_eq!(book.level_counts().await, (50, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_prices_are_not_applied() {
+        let snapshot = BookSnapshot {
+            bids: vec![(100.0, 1.0), (f64::NAN, 1.0)],
+            asks: vec![(0.0, 1.0), (101.0, 1.0)],
+            last_event_time: None,
+        };
+        let deltas = vec![bid(f64::NAN, 2.0), bid(99.0, 1.0), ask(-1.0, 1.0)];
+        let book = OrderBook::from_snapshot(snapshot, MockConnection::new(deltas)).await;
+        settle().await;
+        assert_eq!(book.metrics().errors, 2);
+
+        let error = book.apply(bid(f64::NAN, 1.0).unwrap()).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidPrice(_)));
+        let batch = vec![bid(98.0, 1.0).unwrap(), ask(f64::NAN, 1.0).unwrap()];
+        assert!(book.apply_batch(batch).await.is_err());
+
+        let levels = book.to_snapshot().await;
+        assert_eq!(levels.bids, [(100.0, 1.0), (99.0, 1.0)]);
+        assert_eq!(levels.asks, [(101.0, 1.0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn weighted_mid_weights_each_side_by_its_quantity() {
+        let levels = vec![bid(100.0, 1.0), bid(99.0, 3.0), ask(101.0, 2.0), ask(103.0, 2.0)];
+        let book = book_with(levels).await;
+
+        // (100 * 1 + 99 * 3 + 101 * 2 + 103 * 2) / 8
+        assert_close(book.weighted_mid(2).await, 100.625);
+        // (100 * 1 + 101 * 2) / 3
+        assert_close(book.weighted_mid(1).await, 302.0 / 3.0);
+
+        let one_sided = book_with(vec![bid(100.0, 1.0)]).await;
+        assert_eq!(one_sided.weighted_mid(2).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn effective_spread_is_twice_the_fill_cost_from_mid() {
+        let levels = vec![bid(100.0, 1.0), bid(99.0, 1.0), ask(101.0, 1.0), ask(102.0, 2.0)];
+        let book = book_with(levels).await;
+
+        // Mid 100.5, buying 2 fills at 101.5 and selling 2 at 99.5.
+        assert_close(book.effective_spread(Side::Ask, 2.0).await, 2.0);
+        assert_close(book.effective_spread(Side::Bid, 2.0).await, 2.0);
+        // Buying 0.5 fills at 101.
+        assert_close(book.effective_spread(Side::Ask, 0.5).await, 1.0);
+        assert_eq!(book.effective_spread(Side::Bid, 3.0).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concentration_is_the_herfindahl_index_of_quantities() {
+        let levels = vec![ask(101.0, 1.0), ask(102.0, 1.0), ask(103.0, 2.0), bid(100.0, 2.0)];
+        let book = book_with(levels).await;
+
+        // Shares of 0.25, 0.25 and 0.5.
+        assert_close(book.concentration(Side::Ask, 3).await, 0.375);
+        assert_close(book.concentration(Side::Ask, 2).await, 0.5);
+        assert_close(book.concentration(Side::Bid, 1).await, 1.0);
+        assert_eq!(book.concentration(Side::Ask, 4).await, None);
+        assert_eq!(book.concentration(Side::Bid, 0).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_new_quantity_at_a_present_price_replaces_the_old_one() {
+        let book = book_with(vec![bid(100.0, 5.0)]).await;
+        book.apply(bid(100.0, 9.0).unwrap()).await.unwrap();
+
+        assert_eq!(book.top_bid_ask_qty().await, (Some((100.0, 9.0)), None));
+        assert_eq!(book.level_counts().await, (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn books_of_other_number_types() {
+        fn details<P>(price: P, quantity: P) -> OrderDetails<P> {
+            OrderDetails { price, quantity, event_time: None }
+        }
+        let orders = vec![
+            Ok(Order::Bid(details(99.5f32, 1.0))),
+            Ok(Order::Bid(details(100.25, 2.0))),
+            Ok(Order::Ask(details(101.0, 3.0))),
+        ];
+        let book = OrderBook::create(MockConnection::new(orders)).await;
+        // Integer prices, e.g. in ticks.
+        let orders = vec![Ok(Order::Bid(details(9_950i64, 1))), Ok(Order::Ask(details(10_100, 3)))];
+        let ticks = OrderBook::create(MockConnection::new(orders)).await;
+        settle().await;
+
+        assert_eq!(book.top_bid_ask().await, (Some(100.25), Some(101.0)));
+        assert_eq!(book.depth(2).await.0, [(100.25, 2.0), (99.5, 1.0)]);
+        assert_eq!(ticks.top_bid_ask().await, (Some(9_950), Some(10_100)));
+        assert_eq!(ticks.top_bid_ask_qty().await, (Some((9_950, 1)), Some((10_100, 3))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snapshot_round_trips_through_json() {
+        let timed = |order: Result<Order, Error>, millis| {
+            order.map(|mut order| {
+                let (Order::Bid(details) | Order::Ask(details)) = &mut order;
+                details.event_time = Some(ExchangeTime(millis));
+                order
+            })
+        };
+        let book = book_with(vec![
+            bid(99.0, 1.0),
+            ask(102.0, 2.0),
+            timed(bid(100.0, 3.0), 5),
+            timed(ask(101.0, 4.0), 7),
+        ])
+        .await;
+
+        let snapshot = book.to_snapshot().await;
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "bids": [[100.0, 3.0], [99.0, 1.0]],
+                "asks": [[101.0, 4.0], [102.0, 2.0]],
+                "last_event_time": 7,
+            })
+        );
+        assert_eq!(serde_json::from_value::<BookSnapshot>(json).unwrap(), snapshot);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn level_counts_per_side() {
+        let book = book_with(vec![bid(99.0, 1.0), bid(98.0, 1.0), bid(97.0, 1.0), ask(101.0, 1.0)])
+            .await;
+        assert_eq!(book.level_counts().await, (3, 1));
+
+        book.apply(bid(98.0, 0.0).unwrap()).await.unwrap();
+        assert_eq!(book.level_counts().await, (2, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn crossing_update_removes_the_stale_levels() {
+        /// Streams a book, then a second later a bid through its two best asks.
+        struct Crossing;
+
+        #[async_trait]
+        impl Connection for Crossing {
+            async fn stream(&self) -> Result<ConnectionStream, Error> {
+                let book = [bid(99.0, 1.0), ask(101.0, 1.0), ask(102.0, 1.0), ask(103.0, 1.0)];
+                let crossing = async {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    bid(102.5, 1.0)
+                };
+                let items = futures::stream::iter(book).chain(futures::stream::once(crossing));
+                Ok(Box::pin(items.chain(futures::stream::pending())))
+            }
+        }
+
+        let book = OrderBook::create(Crossing).await;
+        settle().await;
+        assert_eq!(book.top_bid_ask().await, (Some(99.0), Some(101.0)));
+        assert!(!book.is_crossed().await);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!book.is_crossed().await);
+        assert_eq!(book.top_bid_ask().await, (Some(102.5), Some(103.0)));
+        assert_eq!(book.level_counts().await, (2, 1));
+
+        // As are orders applied directly.
+        book.apply(bid(104.0, 1.0).unwrap()).await.unwrap();
+        assert!(!book.is_crossed().await);
+        assert_eq!(book.top_bid_ask().await, (Some(104.0), None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn applied_orders_are_counted_and_uncrossed() {
+        let book = book_with(vec![ask(101.0, 1.0), ask(102.0, 1.0)]).await;
+        book.apply(bid(100.0, 1.0).unwrap()).await.unwrap();
+        // Uncrossed once the batch is applied, so its removal of the crossed ask is not absent.
+        let batch = vec![bid(101.5, 1.0).unwrap(), ask(101.0, 0.0).unwrap()];
+        book.apply_batch(batch).await.unwrap();
+
+        assert_eq!(book.metrics().deltas_applied, 5);
+        assert_eq!(book.metrics().absent_removals, 0);
+        assert!(!book.is_crossed().await);
+        assert_eq!(book.top_bid_ask().await, (Some(101.5), Some(102.0)));
+        book.apply_batch(vec![bid(102.0, 1.0).unwrap()]).await.unwrap();
+        assert_eq!(book.top_bid_ask().await, (Some(102.0), None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn levels_beyond_the_maximum_depth_are_pruned() {
+        let mut items: Vec<_> = (1..=100).map(|price| bid(price as f64, 1.0)).collect();
+        items.extend((101..=200).rev().map(|price| ask(price as f64, 1.0)));
+        // Removing a pruned level is not an absent removal.
+        items.push(bid(50.0, 0.0));
+        let options = OrderBookOptions { max_depth: Some(10), ..Default::default() };
+        let book = OrderBook::create_with_options(MockConnection::new(items), options).await;
+        settle().await;
+
+        assert_eq!(book.level_counts().await, (10, 10));
+        let (bids, asks) = book.depth(10).await;
+        assert_eq!(bids.first(), Some(&(100.0, 1.0)));
+        assert_eq!(bids.last(), Some(&(91.0, 1.0)));
+        assert_eq!(asks.first(), Some(&(101.0, 1.0)));
+        assert_eq!(asks.last(), Some(&(110.0, 1.0)));
+        assert_eq!(book.metrics().levels_pruned, 180);
+        assert_eq!(book.metrics().absent_removals, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn best_bid_and_ask() {
+        let orders = vec![bid(99.0, 1.0), bid(100.0, 1.0), ask(102.0, 1.0), ask(101.0, 1.0)];
+        let book = book_with(orders).await;
+        assert_eq!(book.best_bid().await, Some(100.0));
+        assert_eq!(book.best_ask().await, Some(101.0));
+
+        let bids_only = book_with(vec![bid(99.0, 1.0)]).await;
+        assert_eq!(bids_only.best_bid().await, Some(99.0));
+        assert_eq!(bids_only.best_ask().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debug_dump_includes_the_state_and_metrics() {
+        let options = OrderBookOptions { max_depth: Some(1), ..Default::default() };
+        let items = vec![bid(100.0, 1.0), bid(99.0, 1.0), ask(101.0, 1.0), ask(102.0, 0.0)];
+        let book = OrderBook::create_with_options(MockConnection::new(items), options).await;
+        settle().await;
+
+        let dump = book.debug_dump().await;
+        assert_eq!((dump.bids, dump.asks), (vec![(100.0, 1.0)], vec![(101.0, 1.0)]));
+        assert_eq!(dump.state, BookState::Live);
+        assert_eq!(dump.metrics.deltas_applied, 4);
+        assert_eq!(dump.metrics.levels_pruned, 1);
+        assert_eq!(dump.options.max_depth, Some(1));
+        let json = serde_json::to_value(book.debug_dump().await).unwrap();
+        assert_eq!(json["state"], "Live");
+        assert_eq!(json["metrics"]["deltas_applied"], 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mid_price_is_halfway_between_the_top_bid_and_ask() {
+        let book = book_with(vec![bid(100.0, 1.0), ask(102.0, 1.0)]).await;
+        assert_eq!(book.mid_price().await, Some(101.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spread_needs_both_sides() {
+        let book = book_with(vec![bid(100.0, 1.0), bid(99.0, 1.0), ask(102.5, 1.0)]).await;
+        assert_eq!(book.spread().await, Some(2.5));
+
+        let bids_only = book_with(vec![bid(100.0, 1.0)]).await;
+        assert_eq!(bids_only.spread().await, None);
+        let asks_only = book_with(vec![ask(102.0, 1.0)]).await;
+        assert_eq!(asks_only.spread().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn depth_returns_the_top_levels_best_first() {
+        let mut levels = Vec::new();
+        for i in 0..5 {
+            levels.push(bid(100.0 - i as f64, 1.0 + i as f64));
+            levels.push(ask(101.0 + i as f64, 2.0 + i as f64));
+        }
+        let book = book_with(levels).await;
+
+        let (bids, asks) = book.depth(3).await;
+        assert_eq!(bids, [(100.0, 1.0), (99.0, 2.0), (98.0, 3.0)]);
+        assert_eq!(asks, [(101.0, 2.0), (102.0, 3.0), (103.0, 4.0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn vwap_of_filling_a_quantity() {
+        let levels = vec![ask(101.0, 1.0), ask(102.0, 2.0), bid(100.0, 1.0), bid(99.0, 1.0)];
+        let book = book_with(levels).await;
+
+        // Exactly the first two ask levels.
+        assert_close(book.vwap(Side::Ask, 3.0).await, (101.0 + 2.0 * 102.0) / 3.0);
+        // Part of the last level.
+        assert_close(book.vwap(Side::Ask, 2.0).await, 101.5);
+        assert_close(book.vwap(Side::Bid, 1.5).await, (100.0 + 0.5 * 99.0) / 1.5);
+        // More than the side holds.
+        assert_eq!(book.vwap(Side::Ask, 3.5).await, None);
+        assert_eq!(book.vwap(Side::Bid, 0.0).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnection_delays_grow_then_reset() {
+        let script = vec![Err(refused()), Err(refused()), Ok(vec![Err(closed())]), Err(refused())];
+        let connection = ScriptedConnection::new(script);
+        let calls = connection.calls.clone();
+        let _book = OrderBook::create(connection).await;
+        while calls.lock().unwrap().len() < 5 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Each delay is jittered down to half of the current delay, which starts at 250ms and
+        // doubles per failed attempt.
+        let calls = calls.lock().unwrap();
+        let delays: Vec<_> = calls.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        let ms = Duration::from_millis;
+        assert!(delays[0] >= ms(125) && delays[0] <= ms(260), "{delays:?}");
+        assert!(delays[1] >= ms(250) && delays[1] <= ms(510), "{delays:?}");
+        assert!(delays[1] >= delays[0], "{delays:?}");
+        // Reset by the successful connection, and growing again.
+        assert!(delays[2] >= ms(125) && delays[2] <= ms(260), "{delays:?}");
+        assert!(delays[3] >= ms(250) && delays[3] <= ms(510), "{delays:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnection_waits_for_the_retry_after() {
+        let rate_limited = Error::RateLimited { retry_after: Duration::from_secs(7) };
+        let connection = ScriptedConnection::new(vec![Err(rate_limited)]);
+        let calls = connection.calls.clone();
+        let _book = OrderBook::create(connection).await;
+        while calls.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let calls = calls.lock().unwrap();
+        assert!(calls[1] - calls[0] >= Duration::from_secs(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn state_follows_the_connection() {
+        /// Streams a bid, then fails after a second on the first connection only.
+        struct FailingOnce(AtomicU64);
+
+        #[async_trait]
+        impl Connection for FailingOnce {
+            async fn stream(&self) -> Result<ConnectionStream, Error> {
+                let items = futures::stream::iter([bid(100.0, 1.0)]);
+                if self.0.fetch_add(1, AtomicOrdering::Relaxed) > 0 {
+                    return Ok(Box::pin(items.chain(futures::stream::pending())));
+                }
+                let error = async {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    Err(closed())
+                };
+                Ok(Box::pin(items.chain(futures::stream::once(error))))
+            }
+        }
+
+        let book = OrderBook::create(FailingOnce(AtomicU64::new(0))).await;
+        let mut receiver = book.watch_state();
+        let states = Arc::new(Mutex::new(vec![*receiver.borrow_and_update()]));
+        let recorded = states.clone();
+        tokio::spawn(async move {
+            while receiver.changed().await.is_ok() {
+                recorded.lock().unwrap().push(*receiver.borrow_and_update());
             }
+        });
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [BookState::Connecting, BookState::Live, BookState::Reconnecting, BookState::Live]
+        );
+        assert_eq!(book.state(), BookState::Live);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn periodic_resync_rebuilds_the_book_from_a_new_snapshot() {
+        let script = vec![
+            Ok(vec![bid(100.0, 1.0), ask(101.0, 1.0)]),
+            Ok(vec![bid(99.0, 2.0), ask(102.0, 2.0)]),
+        ];
+        let connection = ScriptedConnection::new(script);
+        let calls = connection.calls.clone();
+        let interval = Duration::from_secs(600);
+        let options = OrderBookOptions { resync_interval: Some(interval), ..Default::default() };
+        let book = OrderBook::create_with_options(connection, options).await;
+        settle().await;
+        assert_eq!(book.top_bid_ask_qty().await, (Some((100.0, 1.0)), Some((101.0, 1.0))));
+
+        tokio::time::sleep(interval).await;
+        assert_eq!(book.top_bid_ask_qty().await, (Some((99.0, 2.0)), Some((102.0, 2.0))));
+        assert_eq!(book.level_counts().await, (1, 1));
+        // Reconnected at once, without a backoff delay.
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1] - calls[0], interval);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn error_callbacks_receive_each_error() {
+        let invalid = Error::ParseNumber("1.2.3".to_owned());
+        let script = vec![Err(refused()), Ok(vec![bid(100.0, 1.0), Err(invalid), Err(closed())])];
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let recorded = errors.clone();
+        let options = OrderBookOptions {
+            on_error: Some(Arc::new(move |e: &Error| {
+                recorded.lock().unwrap().push((e.to_string(), e.is_fatal()))
+            })),
+            ..Default::default()
+        };
+        let _book = OrderBook::create_with_options(ScriptedConnection::new(script), options).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let expected = [
+            (refused().to_string(), true),
+            ("Invalid number 1.2.3".to_owned(), false),
+            (closed().to_string(), true),
+        ];
+        assert_eq!(*errors.lock().unwrap(), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_maximum_reconnect_attempts() {
+        let connection = ScriptedConnection::new((0..10).map(|_| Err(refused())).collect());
+        let calls = connection.calls.clone();
+        let options = OrderBookOptions { max_reconnect_attempts: Some(3), ..Default::default() };
+        let book = OrderBook::create_with_options(connection, options).await;
+        let mut state = book.watch_state();
+        tokio::time::timeout(Duration::from_secs(60), state.wait_for(|s| *s == BookState::Failed))
+            .await
+            .expect("did not give up")
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(calls.lock().unwrap().len(), 3);
+        assert!(book.has_failed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn metrics_count_deltas_errors_and_reconnects() {
+        let script = vec![Ok(vec![bid(100.0, 1.0), Err(closed())])];
+        let book = OrderBook::create(ScriptedConnection::new(script)).await;
+        let mut state = book.watch_state();
+        state.wait_for(|s| *s == BookState::Reconnecting).await.unwrap();
+        state.wait_for(|s| *s == BookState::Live).await.unwrap();
+        settle().await;
+
+        let metrics = book.metrics();
+        assert_eq!(metrics.deltas_applied, 1);
+        assert_eq!(metrics.errors, 1);
+        assert_eq!(metrics.reconnects, 1);
+        assert_eq!(metrics.levels_pruned, 0);
+    }
+
+    #[test]
+    fn latency_from_the_event_time() {
+        let event_time = ExchangeTime(1_700_000_000_000);
+        let at = |micros| event_time.to_system_time() + Duration::from_micros(micros);
+        assert_eq!(latency_us(event_time, at(1_500)), 1_500);
+        assert_eq!(latency_us(event_time, at(0)), 0);
+        // The local clock is behind the exchange's.
+        let behind = event_time.to_system_time() - Duration::from_millis(2);
+        assert_eq!(latency_us(event_time, behind), -2_000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_is_recorded_for_timed_updates() {
+        let book = book_with(vec![bid(100.0, 1.0)]).await;
+        assert_eq!(book.metrics().apply_latency_us, None);
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let event_time = ExchangeTime(now.as_millis() as u64 - 1_000);
+        let order = OrderDetails { price: 99.0, quantity: 1.0, event_time: Some(event_time) };
+        book.apply(Order::Bid(order)).await.unwrap();
+        let latency = book.metrics().apply_latency_us.unwrap();
+        assert!((1_000_000..60_000_000).contains(&latency), "{latency}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn maintenance_keeps_the_levels_as_stale() {
+        let now = SystemTime::now();
+        let window = MaintenanceWindow { start: now, end: now + Duration::from_secs(3600) };
+        let options = OrderBookOptions { maintenance_windows: vec![window], ..Default::default() };
+        let saved = BookSnapshot { bids: vec![(100.0, 1.0)], asks: vec![], last_event_time: None };
+        let connection = ScriptedConnection::new(vec![Err(refused()), Err(refused())]);
+        let calls = connection.calls.clone();
+        let book = OrderBook::from_snapshot_with_options(saved, connection, options).await;
+        settle().await;
+
+        assert_eq!(book.state(), BookState::Stale);
+        assert_eq!(book.top_bid_ask().await, (Some(100.0), None));
+        tokio::time::sleep(MAINTENANCE_RETRY_INTERVAL).await;
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1] - calls[0], MAINTENANCE_RETRY_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_stops_the_background_tasks() {
+        let options = OrderBookOptions {
+            heartbeat_interval: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let book =
+            OrderBook::create_with_options(MockConnection::new(vec![bid(100.0, 1.0)]), options)
+                .await;
+        settle().await;
+
+        let stopped = tokio::time::timeout(Duration::from_secs(1), book.shutdown()).await;
+        assert!(stopped.is_ok(), "tasks still running");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscribers_receive_changes_of_the_top() {
+        let book = book_with(Vec::new()).await;
+        let mut updates = book.subscribe();
+        for order in [bid(100.0, 1.0), bid(99.0, 1.0), bid(101.0, 1.0)] {
+            book.apply(order.unwrap()).await.unwrap();
         }
+
+        assert_eq!(updates.recv().await.unwrap(), BookUpdate { bid: Some(100.0), ask: None });
+        assert_eq!(updates.recv().await.unwrap(), BookUpdate { bid: Some(101.0), ask: None });
+        // The level below the top did not change it.
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn last_update_time_is_the_latest_event_time() {
+        let timed = |price, time: u64| {
+            Ok(Order::Bid(OrderDetails { price, quantity: 1.0, event_time: Some(time.into()) }))
+        };
+        let book = book_with(vec![timed(100.0, 1_000), timed(101.0, 2_000)]).await;
+        assert_eq!(book.last_update_time().await, Some(2_000));
+
+        // Fresh up to and including the maximum age.
+        assert!(!book.is_stale(500, 2_400).await);
+        assert!(!book.is_stale(500, 2_500).await);
+        assert!(book.is_stale(500, 2_501).await);
+        // A clock behind the exchange's is not stale.
+        assert!(!book.is_stale(500, 1_000).await);
+
+        // Unknown without event times.
+        let untimed = book_with(vec![bid(100.0, 1.0)]).await;
+        assert_eq!(untimed.last_update_time().await, None);
+        assert!(untimed.is_stale(500, 2_000).await);
     }
-}
\ No newline at end of file
+
+    #[tokio::test(start_paused = true)]
+    async fn volume_up_to_a_price_threshold() {
+        let levels = vec![
+            bid(100.0, 1.0),
+            bid(99.0, 2.0),
+            bid(98.0, 4.0),
+            ask(101.0, 0.5),
+            ask(102.0, 1.5),
+            ask(103.0, 3.0),
+        ];
+        let book = book_with(levels).await;
+
+        assert_eq!(book.bid_volume_above(99.0).await, 3.0);
+        assert_eq!(book.bid_volume_above(98.5).await, 3.0);
+        assert_eq!(book.bid_volume_above(0.0).await, 7.0);
+        assert_eq!(book.bid_volume_above(100.5).await, 0.0);
+        assert_eq!(book.ask_volume_below(102.0).await, 2.0);
+        assert_eq!(book.ask_volume_below(1_000.0).await, 5.0);
+        assert_eq!(book.ask_volume_below(100.0).await, 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn imbalance_of_balanced_bid_heavy_and_one_sided_books() {
+        let balanced = book_with(vec![bid(100.0, 2.0), ask(101.0, 2.0)]).await;
+        assert_close(balanced.imbalance(5).await, 0.0);
+
+        let levels = vec![bid(100.0, 2.0), bid(99.0, 4.0), ask(101.0, 1.0), ask(102.0, 1.0)];
+        let bid_heavy = book_with(levels).await;
+        // (6 - 2) / 8 over both levels, (2 - 1) / 3 over the top level.
+        assert_close(bid_heavy.imbalance(2).await, 0.5);
+        assert_close(bid_heavy.imbalance(1).await, 1.0 / 3.0);
+
+        let no_asks = book_with(vec![bid(100.0, 2.0)]).await;
+        assert_eq!(no_asks.imbalance(5).await, None);
+    }
+}
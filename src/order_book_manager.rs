@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use crate::connection::Connection;
+use crate::order_book::OrderBook;
+
+/// Order books for several symbols, each with its own connection and background task.
+#[derive(Default)]
+pub struct OrderBookManager {
+    books: HashMap<String, OrderBook>,
+}
+
+impl OrderBookManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an order book for `symbol` using the specified connection. A book already managed
+    /// for the symbol is replaced and stopped.
+    pub async fn add_symbol<C: Connection>(&mut self, symbol: String, connection: C) {
+        let book = OrderBook::create(connection).await;
+        if let Some(previous) = self.books.insert(symbol, book) {
+            previous.shutdown().await;
+        }
+    }
+
+    /// Returns the order book for `symbol`, if managed.
+    pub fn get(&self, symbol: &str) -> Option<&OrderBook> {
+        self.books.get(symbol)
+    }
+
+    /// Returns the (possibly empty) top bid and ask for `symbol`, or `None` if it is not managed.
+    pub async fn top_bid_ask(&self, symbol: &str) -> Option<(Option<f64>, Option<f64>)> {
+        Some(self.books.get(symbol)?.top_bid_ask().await)
+    }
+
+    /// Stops every book's background tasks and waits for them to finish.
+    pub async fn shutdown_all(self) {
+        futures::future::join_all(self.books.into_values().map(OrderBook::shutdown)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::connection::{MockConnection, Order, OrderDetails};
+
+    #[tokio::test(start_paused = true)]
+    async fn books_are_independent_per_symbol() {
+        let details = |price| OrderDetails { price, quantity: 1.0, event_time: None };
+        let mut manager = OrderBookManager::new();
+        let btc = MockConnection::new(vec![Ok(Order::Bid(details(100.0)))]);
+        manager.add_symbol("BTCUSDT".to_owned(), btc).await;
+        let eth = MockConnection::new(vec![Ok(Order::Ask(details(5.0)))]);
+        manager.add_symbol("ETHUSDT".to_owned(), eth).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(manager.top_bid_ask("BTCUSDT").await, Some((Some(100.0), None)));
+        assert_eq!(manager.top_bid_ask("ETHUSDT").await, Some((None, Some(5.0))));
+        assert_eq!(manager.top_bid_ask("SOLUSDT").await, None);
+        assert!(manager.get("ETHUSDT").is_some());
+        manager.shutdown_all().await;
+    }
+}
@@ -0,0 +1,390 @@
+//! Binance connections against local REST and websocket servers.
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use byte_test::connection::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Snapshot up to update 10, with a bid at 100 and an ask at 101.
+const SNAPSHOT: &str = r#"{"lastUpdateId":10,"bids":[["100.0","1.0"]],"asks":[["101.0","1.0"]]}"#;
+
+/// An HTTP 200 response with a JSON body.
+fn json_response(body: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// Serves the responses to successive HTTP requests, repeating the last one. Returns the
+/// base URL.
+async fn serve_rest(responses: Vec<String>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut responses = responses.into_iter();
+        let mut last = None;
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 4096];
+            let _ = socket.read(&mut request).await;
+            if let Some(response) = responses.next() {
+                last = Some(response);
+            }
+            let Some(response) = &last else { return };
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+    format!("http://{}", addr)
+}
+
+/// Accepts HTTP connections without ever responding. Returns the base URL.
+async fn serve_silence() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut sockets = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            sockets.push(socket);
+        }
+    });
+    format!("http://{}", addr)
+}
+
+/// Accepts one websocket connection and sends it the frames, then keeps it open. Returns the
+/// base URL.
+async fn serve_ws(frames: Vec<Message>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+        for frame in frames {
+            ws.send(frame).await.unwrap();
+        }
+        while let Some(Ok(_)) = ws.next().await {}
+    });
+    format!("ws://{}", addr)
+}
+
+/// A depth update for BTCUSDT covering `first..=last`, with a bid level.
+fn delta(first: u64, last: u64, bid: &str) -> Message {
+    Message::Text(format!(
+        r#"{{"e":"depthUpdate","E":1,"s":"BTCUSDT","U":{},"u":{},"b":[["{}","1.0"]],"a":[]}}"#,
+        first, last, bid
+    ))
+}
+
+/// Side, price and quantity of an order.
+fn level(order: &Order) -> (&'static str, f64, f64) {
+    match order {
+        Order::Bid(details) => ("bid", details.price, details.quantity),
+        Order::Ask(details) => ("ask", details.price, details.quantity),
+    }
+}
+
+/// The error of a failed item.
+fn error<T>(item: &Result<T, Error>) -> &Error {
+    match item {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e,
+    }
+}
+
+/// Takes the next `n` items, failing if the stream stalls.
+async fn take<S: Stream + Unpin>(stream: &mut S, n: usize) -> Vec<S::Item> {
+    let mut items = Vec::new();
+    for _ in 0..n {
+        let item = tokio::time::timeout(Duration::from_secs(5), stream.next()).await;
+        items.push(item.expect("stream stalled").expect("stream ended"));
+    }
+    items
+}
+
+#[tokio::test]
+async fn pings_do_not_end_the_depth_stream() {
+    let rest = serve_rest(vec![json_response(SNAPSHOT)]).await;
+    let ws = serve_ws(vec![
+        Message::Ping(vec![1]),
+        delta(11, 11, "99.0"),
+        Message::Ping(vec![2]),
+        delta(12, 12, "98.0"),
+    ])
+    .await;
+    let connection = BinanceConnection::new("BTCUSDT").base_urls(&rest, &ws);
+    let mut stream = connection.stream().await.unwrap();
+
+    let levels: Vec<_> = take(&mut stream, 4)
+        .await
+        .into_iter()
+        .map(|order| level(&order.unwrap()))
+        .collect();
+    assert_eq!(
+        levels,
+        [("ask", 101.0, 1.0), ("bid", 100.0, 1.0), ("bid", 99.0, 1.0), ("bid", 98.0, 1.0)]
+    );
+}
+
+#[tokio::test]
+async fn pings_do_not_end_the_combined_stream() {
+    let rest = serve_rest(vec![json_response(SNAPSHOT)]).await;
+    let envelope = |message: Message| {
+        Message::Text(format!(
+            r#"{{"stream":"btcusdt@depth","data":{}}}"#,
+            message.into_text().unwrap()
+        ))
+    };
+    let ws = serve_ws(vec![
+        Message::Ping(vec![1]),
+        envelope(delta(11, 11, "99.0")),
+        Message::Ping(vec![2]),
+        envelope(delta(12, 12, "98.0")),
+    ])
+    .await;
+    let connection = BinanceCombinedConnection::new(vec!["BTCUSDT".to_owned()])
+        .base_urls(&rest, &ws);
+    let mut stream = connection.stream().await.unwrap();
+
+    let levels: Vec<_> = take(&mut stream, 4)
+        .await
+        .into_iter()
+        .map(|order| order.unwrap())
+        .inspect(|order| assert_eq!(&*order.symbol, "BTCUSDT"))
+        .map(|order| level(&order.order))
+        .collect();
+    assert_eq!(
+        levels,
+        [("ask", 101.0, 1.0), ("bid", 100.0, 1.0), ("bid", 99.0, 1.0), ("bid", 98.0, 1.0)]
+    );
+}
+
+#[tokio::test]
+async fn pings_do_not_end_the_trade_stream() {
+    let trade = |price: &str| {
+        Message::Text(format!(r#"{{"e":"trade","T":5,"p":"{}","q":"2","m":true}}"#, price))
+    };
+    let ws = serve_ws(vec![
+        Message::Ping(vec![1]),
+        trade("100.5"),
+        Message::Ping(vec![2]),
+        trade("101"),
+    ])
+    .await;
+    let connection = BinanceConnection::new("BTCUSDT").base_urls("http://127.0.0.1:1", &ws);
+    let mut trades = connection.trades().await.unwrap();
+
+    let prices: Vec<_> =
+        take(&mut trades, 2).await.into_iter().map(|trade| trade.unwrap().price).collect();
+    assert_eq!(prices, [100.5, 101.0]);
+}
+
+#[tokio::test]
+async fn unparseable_delta_leaves_a_gap() {
+    let rest = serve_rest(vec![json_response(SNAPSHOT)]).await;
+    let ws = serve_ws(vec![delta(11, 11, "99.0"), delta(12, 12, "1.2.3"), delta(13, 13, "98.0")])
+        .await;
+    let connection = BinanceConnection::new("BTCUSDT").base_urls(&rest, &ws);
+    let mut stream = connection.stream().await.unwrap();
+
+    let items = take(&mut stream, 5).await;
+    assert_eq!(level(items[2].as_ref().unwrap()), ("bid", 99.0, 1.0));
+    // The bad delta is skipped, but its levels are missing, so the next delta fails the gap
+    // check and the book resyncs.
+    let skipped = error(&items[3]);
+    assert!(matches!(skipped, Error::ParseNumber(_)) && !skipped.is_fatal());
+    let gap = error(&items[4]);
+    assert!(matches!(gap, Error::UnexpectedItem(_)) && gap.is_fatal());
+    assert!(stream.next().await.is_none());
+}
+
+#[tokio::test]
+async fn snapshot_requests_use_the_injected_client() {
+    let rest = serve_silence().await;
+    let ws = serve_ws(Vec::new()).await;
+    let client = reqwest::Client::builder().timeout(Duration::from_millis(1)).build().unwrap();
+    let connection = BinanceConnection::new("BTCUSDT")
+        .base_urls(&rest, &ws)
+        .http_client(Arc::new(client));
+
+    let result = tokio::time::timeout(Duration::from_secs(5), connection.stream()).await;
+    match result.expect("snapshot request hung") {
+        Err(Error::Rest(e)) => assert!(e.is_timeout(), "{e}"),
+        Err(e) => panic!("unexpected error {e}"),
+        Ok(_) => panic!("connected without a snapshot"),
+    }
+}
+
+#[tokio::test]
+async fn delayed_snapshot_times_out() {
+    let rest = serve_silence().await;
+    let ws = serve_ws(vec![delta(11, 11, "99.0")]).await;
+    let connection = BinanceConnection::new("BTCUSDT")
+        .base_urls(&rest, &ws)
+        .snapshot_timeout(Duration::from_millis(50));
+
+    let result = tokio::time::timeout(Duration::from_secs(5), connection.stream()).await;
+    match result.expect("snapshot request hung") {
+        Err(Error::Rest(e)) => assert!(e.is_timeout(), "{e}"),
+        Err(e) => panic!("unexpected error {e}"),
+        Ok(_) => panic!("connected without a snapshot"),
+    }
+}
+
+#[tokio::test]
+async fn polling_emits_the_difference_between_snapshots() {
+    let second = r#"{"lastUpdateId":11,"bids":[["100.0","2.0"],["99.0","1.0"]],"asks":[]}"#;
+    let rest = serve_rest(vec![json_response(SNAPSHOT), json_response(second)]).await;
+    let connection = BinancePollingConnection::new("BTCUSDT")
+        .base_url(&rest)
+        .interval(Duration::from_millis(10));
+    let mut stream = connection.stream().await.unwrap();
+
+    let levels: Vec<_> = take(&mut stream, 5)
+        .await
+        .into_iter()
+        .map(|order| level(&order.unwrap()))
+        .collect();
+    assert_eq!(
+        levels,
+        [
+            ("bid", 100.0, 1.0),
+            ("ask", 101.0, 1.0),
+            ("bid", 99.0, 1.0),
+            ("bid", 100.0, 2.0),
+            ("ask", 101.0, 0.0),
+        ]
+    );
+    // Later polls return the same snapshot, so there is nothing more to apply.
+    let next = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+    assert!(next.is_err());
+}
+
+#[tokio::test]
+async fn polling_waits_for_the_rate_limiter() {
+    /// Counts the requests it lets through.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    #[async_trait]
+    impl RateLimiter for Counting {
+        async fn acquire(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let second = r#"{"lastUpdateId":11,"bids":[["100.0","2.0"]],"asks":[["101.0","1.0"]]}"#;
+    let rest = serve_rest(vec![json_response(SNAPSHOT), json_response(second)]).await;
+    let limiter = Arc::new(Counting::default());
+    let connection = BinancePollingConnection::new("BTCUSDT")
+        .base_url(&rest)
+        .interval(Duration::from_millis(10))
+        .rate_limiter(limiter.clone());
+    let mut stream = connection.stream().await.unwrap();
+    assert_eq!(limiter.0.load(Ordering::Relaxed), 1);
+
+    // The first snapshot's levels, then the second's change.
+    take(&mut stream, 3).await;
+    assert!(limiter.0.load(Ordering::Relaxed) >= 2);
+    // Polling stops with the stream.
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let polls = limiter.0.load(Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(limiter.0.load(Ordering::Relaxed), polls);
+}
+
+#[tokio::test]
+async fn rate_limited_snapshot_gives_the_retry_after() {
+    let response = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 7\r\nContent-Length: 0\r\n\
+                    Connection: close\r\n\r\n";
+    let rest = serve_rest(vec![response.to_owned()]).await;
+    let ws = serve_ws(Vec::new()).await;
+    let connection = BinanceConnection::new("BTCUSDT").base_urls(&rest, &ws);
+
+    let Err(e) = connection.stream().await else { panic!("connected without a snapshot") };
+    assert!(e.is_rate_limited(), "{e}");
+    assert_eq!(e.retry_after(), Some(Duration::from_secs(7)));
+}
+
+#[tokio::test]
+async fn close_frame_gives_its_code_and_reason() {
+    let trade = Message::Text(r#"{"e":"trade","T":5,"p":"100.5","q":"2","m":true}"#.to_owned());
+    let close = CloseFrame { code: CloseCode::Policy, reason: "banned".into() };
+    let ws = serve_ws(vec![trade, Message::Close(Some(close))]).await;
+    let connection = BinanceConnection::new("BTCUSDT").base_urls("http://127.0.0.1:1", &ws);
+    let mut trades = connection.trades().await.unwrap();
+
+    let items = take(&mut trades, 2).await;
+    assert_eq!(items[0].as_ref().unwrap().price, 100.5);
+    match error(&items[1]) {
+        Error::StreamClosed { code, reason } => assert_eq!((*code, &**reason), (1008, "banned")),
+        e => panic!("unexpected error {e}"),
+    }
+}
+
+#[tokio::test]
+async fn futures_deltas_must_follow_the_previous_update() {
+    let futures_delta = |first: u64, last: u64, previous: u64, bid: &str| {
+        Message::Text(format!(
+            r#"{{"e":"depthUpdate","E":1,"s":"BTCUSDT","U":{},"u":{},"pu":{},"b":[["{}","1.0"]],
+                "a":[]}}"#,
+            first, last, previous, bid
+        ))
+    };
+    let rest = serve_rest(vec![json_response(SNAPSHOT)]).await;
+    let ws = serve_ws(vec![
+        futures_delta(5, 12, 4, "99.0"),
+        // Update ids skip ahead between futures deltas, but this one follows the previous.
+        futures_delta(20, 25, 12, "98.0"),
+        futures_delta(26, 30, 24, "97.0"),
+    ])
+    .await;
+    let connection = BinanceFuturesConnection::new("BTCUSDT").base_urls(&rest, &ws);
+    let mut stream = connection.stream().await.unwrap();
+
+    let items = take(&mut stream, 5).await;
+    assert_eq!(level(items[2].as_ref().unwrap()), ("bid", 99.0, 1.0));
+    assert_eq!(level(items[3].as_ref().unwrap()), ("bid", 98.0, 1.0));
+    let gap = error(&items[4]);
+    assert!(matches!(gap, Error::UnexpectedItem(_)) && gap.is_fatal());
+    assert!(stream.next().await.is_none());
+}
+
+#[tokio::test]
+async fn compressed_frames_are_inflated() {
+    let gzip = |message: Message| {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(message.into_text().unwrap().as_bytes()).unwrap();
+        Message::Binary(encoder.finish().unwrap())
+    };
+    let rest = serve_rest(vec![json_response(SNAPSHOT)]).await;
+    let ws = serve_ws(vec![gzip(delta(11, 11, "99.0")), gzip(delta(12, 12, "98.0"))]).await;
+    let connection = BinanceConnection::new("BTCUSDT")
+        .base_urls(&rest, &ws)
+        .compressed_frames(true);
+    let mut stream = connection.stream().await.unwrap();
+
+    let levels: Vec<_> = take(&mut stream, 4)
+        .await
+        .into_iter()
+        .map(|order| level(&order.unwrap()))
+        .collect();
+    assert_eq!(
+        levels,
+        [("ask", 101.0, 1.0), ("bid", 100.0, 1.0), ("bid", 99.0, 1.0), ("bid", 98.0, 1.0)]
+    );
+}